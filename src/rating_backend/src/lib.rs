@@ -1,14 +1,49 @@
 use candid::{CandidType, Deserialize, Principal};
-use ic_cdk::api::time;
+use ic_cdk::api::{canister_self, is_controller, msg_caller, time};
+use ic_cdk::call::Call;
 use ic_cdk_macros::*;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
 use serde::Serialize;
 use std::borrow::Cow;
 use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Bound;
+use std::time::Duration;
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
+const REBUILD_BATCH_SIZE: usize = 500;
+
+// Reputation is a Bayesian average: PRIOR_WEIGHT phantom ratings of PRIOR_MEAN stars are
+// blended with the user's real ratings, so a handful of ratings can't dominate the
+// leaderboard. Verified ratings count VERIFIED_WEIGHT times as much, and every rating's
+// weight halves each REPUTATION_HALF_LIFE.
+const PRIOR_MEAN: f64 = 3.0;
+const PRIOR_WEIGHT: f64 = 5.0;
+const VERIFIED_WEIGHT: f64 = 2.0;
+const REPUTATION_HALF_LIFE: u64 = 180 * 24 * 60 * 60 * 1_000_000_000;
+const MIN_RATINGS_FOR_LEADERBOARD: u32 = 5;
+
+// Raters can edit or retract a rating for this long after submitting it.
+const EDIT_WINDOW: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+const MAX_RATING_EDITS: usize = 5;
+const MAX_REVIEW_LENGTH: usize = 1000;
+
+// Anti-abuse limits. A rater may submit RATER_LIMIT ratings per RATE_LIMIT_WINDOW, and may
+// only rate the same user in the same category once per DUPLICATE_WINDOW unless each rating
+// references a transfer. A user receiving BURST_THRESHOLD ratings within one window, or a
+// ring of up to RING_MAX_LENGTH users rating each other highly within RECIPROCAL_WINDOW, is
+// flagged for moderation. The ring search reads at most RING_SEARCH_LIMIT ratings.
+const RATE_LIMIT_WINDOW: u64 = 60 * 60 * 1_000_000_000;
+const RATER_LIMIT: u32 = 10;
+const DUPLICATE_WINDOW: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+const BURST_THRESHOLD: u32 = 20;
+const RECIPROCAL_WINDOW: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+const RECIPROCAL_MIN_STARS: u8 = 4;
+const RING_MAX_LENGTH: usize = 4;
+const RING_SEARCH_LIMIT: usize = 1000;
+
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
 pub struct Rating {
     pub id: String,
@@ -18,9 +53,30 @@ pub struct Rating {
     pub transaction_id: Option<String>,
     pub rating: u8, // 1-5 stars
     pub review: String,
-    pub category: String, // "quality", "delivery", "communication", "overall"
+    pub category: RatingCategory,
+    pub rated_role: UserRole, // role of the rated user when the rating was submitted
     pub created_at: u64,
     pub is_verified: bool,
+    #[serde(default)]
+    pub is_hidden: bool, // hidden by a moderator; excluded from listings and stats
+    pub retracted_at: Option<u64>, // retracted by the rater; excluded from listings and stats
+    #[serde(default)] // absent on ratings stored before editing existed
+    pub edit_history: Vec<RatingEdit>, // previous versions, oldest first
+    pub response: Option<RatingResponse>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct RatingEdit {
+    pub rating: u8,
+    pub review: String,
+    pub replaced_at: u64,
+}
+
+// The rated user's public reply to a review.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct RatingResponse {
+    pub text: String,
+    pub created_at: u64,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
@@ -29,200 +85,1403 @@ pub struct UserRatingStats {
     pub total_ratings: u32,
     pub average_rating: f64,
     pub star_distribution: Vec<u32>, // [1star, 2star, 3star, 4star, 5star]
-    pub category_ratings: Vec<(String, f64)>, // [(category, avg_rating)]
+    pub category_ratings: Vec<(RatingCategory, f64)>, // [(category, avg_rating)]
+    #[serde(default)] // absent on stats stored before role criteria existed
+    pub overall_score: f64, // category averages weighted by role_criteria
+    #[serde(default = "prior_mean")] // absent on stats stored before reputation existed
+    pub reputation_score: f64, // see PRIOR_MEAN; decays towards it over time
     pub last_updated: u64,
 }
 
-#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
-pub struct RatingReport {
-    pub id: String,
-    pub reporter_id: Principal,
-    pub rating_id: String,
-    pub reason: String,
-    pub description: String,
-    pub created_at: u64,
-    pub status: String, // "pending", "resolved", "dismissed"
+fn prior_mean() -> f64 {
+    PRIOR_MEAN
+}
+
+// Time-decayed rating weights for one user, as of `updated_at`. Because the decay is
+// exponential, both sums can be brought forward to any later time by a single factor.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct ReputationTotals {
+    weight: f64,
+    weighted_sum: f64,
+    updated_at: u64,
+}
+
+impl ReputationTotals {
+    fn decayed_to(&self, now: u64) -> ReputationTotals {
+        let factor = decay_factor(now.saturating_sub(self.updated_at));
+        ReputationTotals {
+            weight: self.weight * factor,
+            weighted_sum: self.weighted_sum * factor,
+            updated_at: now,
+        }
+    }
+
+    fn score(&self) -> f64 {
+        (PRIOR_MEAN * PRIOR_WEIGHT + self.weighted_sum) / (PRIOR_WEIGHT + self.weight)
+    }
+}
+
+fn decay_factor(elapsed: u64) -> f64 {
+    0.5f64.powf(elapsed as f64 / REPUTATION_HALF_LIFE as f64)
+}
+
+// Number of events within a fixed time window, used for rate limits and burst detection.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct ActivityWindow {
+    window_start: u64,
+    count: u32,
+}
+
+impl ActivityWindow {
+    // The count for the window containing `now`, starting a new window if the old one ended.
+    fn current(self, now: u64) -> ActivityWindow {
+        if now >= self.window_start + RATE_LIMIT_WINDOW {
+            ActivityWindow {
+                window_start: now,
+                count: 0,
+            }
+        } else {
+            self
+        }
+    }
+}
+
+// Running totals for one user's ratings in one category.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct CategoryTotals {
+    count: u32,
+    sum: u32,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct RatingReport {
+    pub id: String,
+    pub reporter_id: Principal,
+    pub rating_id: String,
+    pub reason: String,
+    pub description: String,
+    pub created_at: u64,
+    pub status: String, // "pending", "resolved", "dismissed"
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum ModerationAction {
+    Dismiss,
+    HideRating,
+    DeleteRating,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum AuditAction {
+    VerifyRating,
+    ResolveReport(ModerationAction),
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ModerationAuditEntry {
+    pub id: String,
+    pub moderator: Principal,
+    pub action: AuditAction,
+    pub rating_id: String,
+    pub report_id: Option<String>,
+    pub created_at: u64,
+}
+
+// Principals of the other canisters this one calls, set by a controller after deployment.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct CanisterConfig {
+    pub supply_chain_canister: Option<Principal>,
+    pub user_management_canister: Option<Principal>,
+}
+
+// The subset of supply_chain_backend's Transfer that ratings are checked against.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Transfer {
+    product_id: String,
+    from_user: Principal,
+    to_user: Principal,
+    status: String,
+}
+
+// Mirrors of supply_chain_backend's dispute closure report.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum DisputeOutcome {
+    Upheld,
+    PartiallyUpheld,
+    Rejected,
+}
+
+// A dispute closed in supply_chain_backend. The outcome is None for withdrawn disputes.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct DisputeClosure {
+    pub dispute_id: String,
+    pub claimant: Principal,
+    pub respondent: Principal,
+    pub outcome: Option<DisputeOutcome>,
+    pub opened_at: u64,
+    pub closed_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum RatingCategory {
+    Quality,
+    Documentation,
+    Punctuality,
+    Handling,
+    Payment,
+    Communication,
+    Overall,
+}
+
+// Mirrors of user_management_backend's types, used to check roles.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum UserRole {
+    Supplier,
+    Transporter,
+    Warehouse,
+    Retailer,
+    Admin,
+}
+
+// The categories a user with the given role can be rated on, with the weight each one
+// carries in their overall score. Weights for a role sum to 1.
+fn role_criteria(role: &UserRole) -> Vec<(RatingCategory, f64)> {
+    use RatingCategory::*;
+    match role {
+        UserRole::Supplier => vec![
+            (Quality, 0.4),
+            (Documentation, 0.25),
+            (Communication, 0.15),
+            (Overall, 0.2),
+        ],
+        UserRole::Transporter => vec![
+            (Punctuality, 0.4),
+            (Handling, 0.3),
+            (Communication, 0.1),
+            (Overall, 0.2),
+        ],
+        UserRole::Warehouse => vec![
+            (Handling, 0.35),
+            (Documentation, 0.25),
+            (Punctuality, 0.2),
+            (Overall, 0.2),
+        ],
+        UserRole::Retailer => vec![(Payment, 0.5), (Communication, 0.3), (Overall, 0.2)],
+        UserRole::Admin => vec![(Overall, 1.0)],
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+enum AccountStatus {
+    Active,
+    Suspended,
+    Deactivated,
+    Erased,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct User {
+    role: UserRole,
+    status: AccountStatus,
+}
+
+// Ratings and stats stored before categories were typed hold the category names the
+// original frontend sent.
+fn legacy_category(name: &str) -> RatingCategory {
+    match name {
+        "quality" => RatingCategory::Quality,
+        "delivery" => RatingCategory::Punctuality,
+        "communication" => RatingCategory::Communication,
+        _ => RatingCategory::Overall,
+    }
+}
+
+fn migrate_legacy_rating(bytes: &[u8]) -> Rating {
+    let mut rating: serde_json::Value = serde_json::from_slice(bytes).unwrap();
+
+    if let Some(name) = rating["category"].as_str() {
+        rating["category"] = serde_json::to_value(legacy_category(name)).unwrap();
+    }
+    // The rated user's role was not recorded. Admin is rated on Overall alone, so the old
+    // rating still counts towards the average without skewing any role-specific criterion.
+    if rating.get("rated_role").is_none() {
+        rating["rated_role"] = serde_json::to_value(UserRole::Admin).unwrap();
+    }
+
+    serde_json::from_value(rating).unwrap()
+}
+
+fn migrate_legacy_stats(bytes: &[u8]) -> UserRatingStats {
+    let mut stats: serde_json::Value = serde_json::from_slice(bytes).unwrap();
+
+    if let Some(category_ratings) = stats["category_ratings"].as_array() {
+        let category_ratings: Vec<serde_json::Value> = category_ratings
+            .iter()
+            .map(|entry| match entry.get(0).and_then(|name| name.as_str()) {
+                Some(name) => serde_json::json!([legacy_category(name), entry[1]]),
+                None => entry.clone(),
+            })
+            .collect();
+        stats["category_ratings"] = serde_json::Value::Array(category_ratings);
+    }
+
+    serde_json::from_value(stats).unwrap()
+}
+
+impl Storable for Rating {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap_or_else(|_| migrate_legacy_rating(&bytes))
+    }
+
+    // A review and each of its MAX_RATING_EDITS earlier versions can reach
+    // MAX_REVIEW_LENGTH characters, several bytes each once JSON-escaped.
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Storable for UserRatingStats {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap_or_else(|_| migrate_legacy_stats(&bytes))
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Bounded {
+            max_size: 2048,
+            is_fixed_size: false,
+        };
+}
+
+impl Storable for RatingReport {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap()
+    }
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Bounded {
+            max_size: 2048,
+            is_fixed_size: false,
+        };
+}
+
+// Progress of rebuild_rating_stats. Kept in stable memory so that a rebuild interrupted by an
+// upgrade is resumed by post_upgrade.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct RebuildState {
+    running: bool,
+    cursor: Option<String>, // last rating id processed, or None before the first batch
+}
+
+impl Storable for RebuildState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Bounded {
+            max_size: 512,
+            is_fixed_size: false,
+        };
+}
+
+impl Storable for CanisterConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Bounded {
+            max_size: 512,
+            is_fixed_size: false,
+        };
+}
+
+impl Storable for CategoryTotals {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Bounded {
+            max_size: 64,
+            is_fixed_size: false,
+        };
+}
+
+impl Storable for ReputationTotals {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Bounded {
+            max_size: 128,
+            is_fixed_size: false,
+        };
+}
+
+impl Storable for ActivityWindow {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Bounded {
+            max_size: 64,
+            is_fixed_size: false,
+        };
+}
+
+impl Storable for DisputeClosure {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Bounded {
+            max_size: 512,
+            is_fixed_size: false,
+        };
+}
+
+impl Storable for ModerationAuditEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Bounded {
+            max_size: 1024,
+            is_fixed_size: false,
+        };
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    static RATINGS: RefCell<StableBTreeMap<String, Rating, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0)))
+        )
+    );
+
+    static RATING_STATS: RefCell<StableBTreeMap<Principal, UserRatingStats, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+        )
+    );
+
+    static RATING_REPORTS: RefCell<StableBTreeMap<String, RatingReport, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
+        )
+    );
+
+    // "{rater}|{transaction_id}|{category}" -> rating id, enforcing one rating per transfer
+    // per category for each rater.
+    static TRANSFER_RATINGS: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+        )
+    );
+
+    static CANISTER_CONFIG: RefCell<StableCell<CanisterConfig, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
+            CanisterConfig::default(),
+        )
+        .expect("Failed to initialize canister config")
+    );
+
+    static MODERATION_LOG: RefCell<StableBTreeMap<String, ModerationAuditEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+        )
+    );
+
+    // "{user}|{category}" -> running totals backing UserRatingStats.category_ratings.
+    static CATEGORY_TOTALS: RefCell<StableBTreeMap<String, CategoryTotals, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+        )
+    );
+
+    static REPUTATION_TOTALS: RefCell<StableBTreeMap<Principal, ReputationTotals, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+        )
+    );
+
+    // "{rater}|{rated_user}|{rating_id}" -> rating id, for looking up ratings between a pair.
+    static PAIR_RATINGS: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+        )
+    );
+
+    // "{rated_user}|{rating_id}" -> rating id, for looking up the ratings a user received.
+    static RECEIVED_RATINGS: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+        )
+    );
+
+    static RATER_ACTIVITY: RefCell<StableBTreeMap<Principal, ActivityWindow, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+        )
+    );
+
+    static RECEIVED_ACTIVITY: RefCell<StableBTreeMap<Principal, ActivityWindow, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+        )
+    );
+
+    static DISPUTE_CLOSURES: RefCell<StableBTreeMap<String, DisputeClosure, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+        )
+    );
+
+    static REBUILD_STATE: RefCell<StableCell<RebuildState, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+            RebuildState::default(),
+        )
+        .expect("Failed to initialize rebuild state")
+    );
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    // Stats stored before the running totals existed cannot be updated incrementally, so
+    // they are rebuilt once from the ratings.
+    let missing_totals = REPUTATION_TOTALS.with(|r| r.borrow().is_empty())
+        && !RATINGS.with(|r| r.borrow().is_empty());
+    if missing_totals && rebuild_cursor().is_none() {
+        start_rebuild();
+    } else if rebuild_cursor().is_some() {
+        ic_cdk_timers::set_timer(Duration::ZERO, rebuild_next_batch);
+    }
+}
+
+// Some while a rebuild is running. Holds the last rating id processed, or None before the
+// first batch.
+fn rebuild_cursor() -> Option<Option<String>> {
+    let state = REBUILD_STATE.with(|s| s.borrow().get().clone());
+    state.running.then_some(state.cursor)
+}
+
+fn set_rebuild_cursor(cursor: Option<Option<String>>) {
+    let state = RebuildState {
+        running: cursor.is_some(),
+        cursor: cursor.flatten(),
+    };
+    REBUILD_STATE
+        .with(|s| s.borrow_mut().set(state))
+        .expect("Failed to save rebuild state");
+}
+
+fn generate_id() -> String {
+    let timestamp = time();
+    let caller = msg_caller();
+    format!("{}-{}", timestamp, caller.to_text())
+}
+
+#[update]
+fn set_canister_config(config: CanisterConfig) -> Result<CanisterConfig, String> {
+    if !is_controller(&msg_caller()) {
+        return Err("Only controllers can change the canister config".to_string());
+    }
+
+    CANISTER_CONFIG
+        .with(|c| c.borrow_mut().set(config.clone()))
+        .map_err(|e| format!("{:?}", e))?;
+
+    Ok(config)
+}
+
+#[query]
+fn get_canister_config() -> CanisterConfig {
+    CANISTER_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+async fn fetch_transfer(transfer_id: &str) -> Result<Transfer, String> {
+    let canister = CANISTER_CONFIG
+        .with(|c| c.borrow().get().supply_chain_canister)
+        .ok_or("Supply chain canister is not configured".to_string())?;
+
+    Call::bounded_wait(canister, "get_transfer")
+        .with_arg(transfer_id)
+        .await
+        .map_err(|e| format!("Failed to look up transfer: {}", e))?
+        .candid::<Result<Transfer, String>>()
+        .map_err(|e| format!("Failed to decode transfer: {}", e))?
+}
+
+async fn fetch_user(principal: Principal) -> Result<User, String> {
+    let canister = CANISTER_CONFIG
+        .with(|c| c.borrow().get().user_management_canister)
+        .ok_or("User management canister is not configured".to_string())?;
+
+    Call::bounded_wait(canister, "get_user")
+        .with_arg(principal)
+        .await
+        .map_err(|e| format!("Failed to look up user: {}", e))?
+        .candid::<Result<User, String>>()
+        .map_err(|e| format!("Failed to decode user: {}", e))?
+}
+
+// Suspended, deactivated and erased accounts cannot rate or respond.
+async fn require_active(principal: Principal) -> Result<(), String> {
+    if fetch_user(principal).await?.status != AccountStatus::Active {
+        return Err("Account is not active".to_string());
+    }
+
+    Ok(())
+}
+
+// Moderators are users registered with the Admin role in user_management_backend.
+async fn require_moderator(principal: Principal) -> Result<(), String> {
+    let user = fetch_user(principal)
+        .await
+        .map_err(|_| "Only moderators can perform this action".to_string())?;
+
+    match user.role {
+        UserRole::Admin => Ok(()),
+        _ => Err("Only moderators can perform this action".to_string()),
+    }
+}
+
+fn record_moderation(
+    moderator: Principal,
+    action: AuditAction,
+    rating_id: String,
+    report_id: Option<String>,
+) {
+    let entry = ModerationAuditEntry {
+        id: generate_id(),
+        moderator,
+        action,
+        rating_id,
+        report_id,
+        created_at: time(),
+    };
+
+    MODERATION_LOG.with(|l| l.borrow_mut().insert(entry.id.clone(), entry));
+}
+
+fn validate_rating(rating: u8, review: &str) -> Result<(), String> {
+    if !(1..=5).contains(&rating) {
+        return Err("Rating must be between 1 and 5".to_string());
+    }
+
+    if review.chars().count() > MAX_REVIEW_LENGTH {
+        return Err(format!(
+            "Review must be at most {} characters",
+            MAX_REVIEW_LENGTH
+        ));
+    }
+
+    Ok(())
+}
+
+// Whether a rating contributes to stats and shows up in listings.
+fn is_counted(rating: &Rating) -> bool {
+    !rating.is_hidden && rating.retracted_at.is_none()
+}
+
+fn transfer_rating_key(
+    rater: Principal,
+    transaction_id: &str,
+    category: &RatingCategory,
+) -> String {
+    format!("{}|{}|{:?}", rater.to_text(), transaction_id, category)
+}
+
+#[query]
+fn get_rating_criteria(role: UserRole) -> Vec<(RatingCategory, f64)> {
+    role_criteria(&role)
+}
+
+#[update]
+async fn submit_rating(
+    rated_user_id: Principal,
+    product_id: Option<String>,
+    transaction_id: Option<String>,
+    rating: u8,
+    review: String,
+    category: RatingCategory,
+) -> Result<Rating, String> {
+    let caller = msg_caller();
+
+    validate_rating(rating, &review)?;
+
+    if caller == rated_user_id {
+        return Err("Cannot rate yourself".to_string());
+    }
+
+    require_active(caller).await?;
+
+    let rated_role = fetch_user(rated_user_id).await?.role;
+    if !role_criteria(&rated_role)
+        .iter()
+        .any(|(criterion, _)| *criterion == category)
+    {
+        return Err(format!(
+            "{:?} users cannot be rated on {:?}",
+            rated_role, category
+        ));
+    }
+
+    // Ratings that reference a transfer are only accepted if the transfer is completed and
+    // was between the rater and the rated user; those ratings are verified automatically.
+    let mut product_id = product_id;
+    let is_verified = match &transaction_id {
+        Some(transaction_id) => {
+            let transfer = fetch_transfer(transaction_id).await?;
+
+            if transfer.status != "COMPLETED" {
+                return Err("Transfer has not been completed".to_string());
+            }
+
+            let parties = [transfer.from_user, transfer.to_user];
+            if !parties.contains(&caller) || !parties.contains(&rated_user_id) {
+                return Err("Transfer was not between the rater and the rated user".to_string());
+            }
+
+            match &product_id {
+                Some(id) if *id != transfer.product_id => {
+                    return Err("Product does not match the transfer".to_string());
+                }
+                Some(_) => {}
+                None => product_id = Some(transfer.product_id),
+            }
+
+            true
+        }
+        None => false,
+    };
+
+    // Checked after the transfer lookup so concurrent submissions can't both pass.
+    let transfer_key = transaction_id
+        .as_ref()
+        .map(|id| transfer_rating_key(caller, id, &category));
+    if let Some(key) = &transfer_key {
+        if TRANSFER_RATINGS.with(|t| t.borrow().contains_key(key)) {
+            return Err("You have already rated this transfer in this category".to_string());
+        }
+    }
+
+    check_rating_abuse(
+        caller,
+        rated_user_id,
+        &category,
+        &review,
+        transaction_id.is_some(),
+    )?;
+
+    let rating_id = generate_id();
+    let rating_obj = Rating {
+        id: rating_id.clone(),
+        rater_id: caller,
+        rated_user_id,
+        product_id,
+        transaction_id,
+        rating,
+        review,
+        category,
+        rated_role,
+        created_at: time(),
+        is_verified,
+        is_hidden: false,
+        retracted_at: None,
+        edit_history: vec![],
+        response: None,
+    };
+
+    RATINGS.with(|r| r.borrow_mut().insert(rating_id.clone(), rating_obj.clone()));
+    if let Some(key) = transfer_key {
+        TRANSFER_RATINGS.with(|t| t.borrow_mut().insert(key, rating_id));
+    }
+
+    add_to_stats(&rating_obj);
+    record_rating_activity(&rating_obj);
+
+    Ok(rating_obj)
+}
+
+fn pair_prefix(rater: Principal, rated_user: Principal) -> String {
+    format!("{}|{}|", rater.to_text(), rated_user.to_text())
+}
+
+fn ratings_between(rater: Principal, rated_user: Principal) -> Vec<Rating> {
+    let prefix = pair_prefix(rater, rated_user);
+    let ids: Vec<String> = PAIR_RATINGS.with(|p| {
+        p.borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, id)| id)
+            .collect()
+    });
+
+    RATINGS.with(|r| {
+        let r = r.borrow();
+        ids.iter().filter_map(|id| r.get(id)).collect()
+    })
+}
+
+fn check_rating_abuse(
+    rater: Principal,
+    rated_user: Principal,
+    category: &RatingCategory,
+    review: &str,
+    has_transfer: bool,
+) -> Result<(), String> {
+    let now = time();
+
+    let activity = RATER_ACTIVITY
+        .with(|a| a.borrow().get(&rater))
+        .unwrap_or_default()
+        .current(now);
+    if activity.count >= RATER_LIMIT {
+        return Err("Too many ratings submitted, please try again later".to_string());
+    }
+
+    for previous in ratings_between(rater, rated_user) {
+        if !is_counted(&previous) {
+            continue;
+        }
+        if !review.is_empty() && previous.review == review {
+            return Err("You have already submitted this review".to_string());
+        }
+        if !has_transfer
+            && previous.transaction_id.is_none()
+            && previous.category == *category
+            && now < previous.created_at + DUPLICATE_WINDOW
+        {
+            return Err("You have already rated this user in this category recently".to_string());
+        }
+    }
+
+    Ok(())
+}
+
+// Updates the rate limit and burst counters for a new rating and flags suspicious patterns
+// into the moderation queue.
+fn record_rating_activity(rating: &Rating) {
+    let now = rating.created_at;
+
+    PAIR_RATINGS.with(|p| {
+        p.borrow_mut().insert(
+            format!(
+                "{}{}",
+                pair_prefix(rating.rater_id, rating.rated_user_id),
+                rating.id
+            ),
+            rating.id.clone(),
+        )
+    });
+    RECEIVED_RATINGS.with(|r| {
+        r.borrow_mut()
+            .insert(received_key(rating), rating.id.clone())
+    });
+
+    let mut sent = RATER_ACTIVITY
+        .with(|a| a.borrow().get(&rating.rater_id))
+        .unwrap_or_default()
+        .current(now);
+    sent.count += 1;
+    RATER_ACTIVITY.with(|a| a.borrow_mut().insert(rating.rater_id, sent));
+
+    let mut received = RECEIVED_ACTIVITY
+        .with(|a| a.borrow().get(&rating.rated_user_id))
+        .unwrap_or_default()
+        .current(now);
+    received.count += 1;
+    RECEIVED_ACTIVITY.with(|a| {
+        a.borrow_mut()
+            .insert(rating.rated_user_id, received.clone())
+    });
+
+    // When the threshold is reached every rating in the window is flagged, and every later
+    // rating in the same window is flagged as it arrives.
+    let burst = match received.count {
+        count if count == BURST_THRESHOLD => {
+            ratings_received_since(rating.rated_user_id, received.window_start)
+        }
+        count if count > BURST_THRESHOLD => vec![rating.clone()],
+        _ => vec![],
+    };
+    for member in &burst {
+        flag_rating(
+            member,
+            "rating_burst",
+            format!(
+                "{} received {} ratings within an hour",
+                rating.rated_user_id.to_text(),
+                received.count
+            ),
+        );
+    }
+
+    if rating.rating >= RECIPROCAL_MIN_STARS {
+        if let Some(ring) = find_rating_ring(rating) {
+            let ids: Vec<&str> = ring.iter().map(|other| other.id.as_str()).collect();
+            let description = format!(
+                "Ratings {} and {} form a ring of {} users",
+                rating.id,
+                ids.join(", "),
+                ring.len() + 1
+            );
+            flag_rating(rating, "reciprocal_rating", description.clone());
+            for other in &ring {
+                flag_rating(other, "reciprocal_rating", description.clone());
+            }
+        }
+    }
+}
+
+fn received_key(rating: &Rating) -> String {
+    format!("{}|{}", rating.rated_user_id.to_text(), rating.id)
+}
+
+// Rating ids start with their creation time, so the ratings received since `start` are a
+// contiguous range of the index.
+fn ratings_received_since(rated_user: Principal, start: u64) -> Vec<Rating> {
+    let prefix = format!("{}|", rated_user.to_text());
+    let ids: Vec<String> = RECEIVED_RATINGS.with(|r| {
+        r.borrow()
+            .range(format!("{}{}", prefix, start)..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, id)| id)
+            .collect()
+    });
+
+    RATINGS.with(|r| {
+        let r = r.borrow();
+        ids.iter()
+            .filter_map(|id| r.get(id))
+            .filter(|other| is_counted(other) && other.created_at >= start)
+            .collect()
+    })
+}
+
+// Searches breadth-first for a chain of recent high ratings leading from the rated user back
+// to the rater, which together with `rating` closes a ring of at most RING_MAX_LENGTH users.
+// Returns the chain in order, or None if no ring is found within RING_SEARCH_LIMIT reads.
+fn find_rating_ring(rating: &Rating) -> Option<Vec<Rating>> {
+    let now = rating.created_at;
+    let mut reached_by: HashMap<Principal, Rating> = HashMap::new();
+    let mut frontier = vec![rating.rated_user_id];
+    let mut budget = RING_SEARCH_LIMIT;
+
+    for _ in 1..RING_MAX_LENGTH {
+        let mut next = vec![];
+        for user in frontier {
+            let prefix = format!("{}|", user.to_text());
+            let ids: Vec<String> = PAIR_RATINGS.with(|p| {
+                p.borrow()
+                    .range(prefix.clone()..)
+                    .take_while(|(key, _)| key.starts_with(&prefix))
+                    .take(budget)
+                    .map(|(_, id)| id)
+                    .collect()
+            });
+            budget -= ids.len();
+
+            for id in ids {
+                let Some(other) = RATINGS.with(|r| r.borrow().get(&id)) else {
+                    continue;
+                };
+                if !is_counted(&other)
+                    || other.rating < RECIPROCAL_MIN_STARS
+                    || now >= other.created_at + RECIPROCAL_WINDOW
+                {
+                    continue;
+                }
+
+                let target = other.rated_user_id;
+                if target == rating.rater_id {
+                    let mut chain = vec![other];
+                    while chain[0].rater_id != rating.rated_user_id {
+                        chain.insert(0, reached_by[&chain[0].rater_id].clone());
+                    }
+                    return Some(chain);
+                }
+                if target != rating.rated_user_id && !reached_by.contains_key(&target) {
+                    reached_by.insert(target, other);
+                    next.push(target);
+                }
+            }
+
+            if budget == 0 {
+                return None;
+            }
+        }
+        frontier = next;
+    }
+
+    None
+}
+
+// Files a pending report on behalf of the canister itself, so automatic flags show up in
+// get_pending_reports alongside user reports.
+fn flag_rating(rating: &Rating, reason: &str, description: String) {
+    let id = format!("{}-{}", rating.id, reason);
+    // A rating already flagged for this reason keeps its report, including any resolution.
+    if RATING_REPORTS.with(|r| r.borrow().contains_key(&id)) {
+        return;
+    }
+
+    let report = RatingReport {
+        id,
+        reporter_id: canister_self(),
+        rating_id: rating.id.clone(),
+        reason: reason.to_string(),
+        description,
+        created_at: rating.created_at,
+        status: "pending".to_string(),
+    };
+
+    RATING_REPORTS.with(|r| r.borrow_mut().insert(report.id.clone(), report));
+}
+
+// A running rebuild will pick up any rating past its cursor, so applying it here as well
+// would count it twice.
+fn pending_rebuild(rating_id: &String) -> bool {
+    match &rebuild_cursor() {
+        Some(Some(cursor)) => rating_id > cursor,
+        Some(None) => true,
+        None => false,
+    }
+}
+
+fn add_to_stats(rating: &Rating) {
+    if !pending_rebuild(&rating.id) {
+        apply_to_stats(rating, 1, time());
+    }
+}
+
+fn remove_from_stats(rating: &Rating) {
+    if !pending_rebuild(&rating.id) {
+        apply_to_stats(rating, -1, time());
+    }
 }
 
-impl Storable for Rating {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_json::to_vec(self).unwrap())
+// Adds (delta = 1) or removes (delta = -1) one rating from its user's running totals.
+fn apply_to_stats(rating: &Rating, delta: i32, now: u64) {
+    let user_id = rating.rated_user_id;
+    let stars = rating.rating as u32;
+
+    let mut stats = RATING_STATS
+        .with(|s| s.borrow().get(&user_id))
+        .unwrap_or(UserRatingStats {
+            user_id,
+            total_ratings: 0,
+            average_rating: 0.0,
+            star_distribution: vec![0; 5],
+            category_ratings: vec![],
+            overall_score: 0.0,
+            reputation_score: PRIOR_MEAN,
+            last_updated: 0,
+        });
+
+    let bucket = &mut stats.star_distribution[(stars - 1) as usize];
+    *bucket = bucket.saturating_add_signed(delta);
+
+    let category_key = format!("{}|{:?}", user_id.to_text(), rating.category);
+    let mut totals = CATEGORY_TOTALS
+        .with(|c| c.borrow().get(&category_key))
+        .unwrap_or_default();
+    totals.count = totals.count.saturating_add_signed(delta);
+    totals.sum = totals.sum.saturating_add_signed(delta * stars as i32);
+
+    stats
+        .category_ratings
+        .retain(|(category, _)| *category != rating.category);
+    if totals.count == 0 {
+        CATEGORY_TOTALS.with(|c| c.borrow_mut().remove(&category_key));
+    } else {
+        stats.category_ratings.push((
+            rating.category.clone(),
+            totals.sum as f64 / totals.count as f64,
+        ));
+        CATEGORY_TOTALS.with(|c| c.borrow_mut().insert(category_key, totals));
     }
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_json::from_slice(&bytes).unwrap()
+    stats.total_ratings = stats.star_distribution.iter().sum();
+    if stats.total_ratings == 0 {
+        RATING_STATS.with(|s| s.borrow_mut().remove(&user_id));
+        REPUTATION_TOTALS.with(|r| r.borrow_mut().remove(&user_id));
+        return;
     }
 
-    const BOUND: ic_stable_structures::storable::Bound =
-        ic_stable_structures::storable::Bound::Bounded {
-            max_size: 2048,
-            is_fixed_size: false,
-        };
+    let mut reputation = REPUTATION_TOTALS
+        .with(|r| r.borrow().get(&user_id))
+        .unwrap_or_default()
+        .decayed_to(now);
+    let weight = rating_weight(rating, now) * delta as f64;
+    reputation.weight = (reputation.weight + weight).max(0.0);
+    reputation.weighted_sum = (reputation.weighted_sum + weight * stars as f64).max(0.0);
+    stats.reputation_score = reputation.score();
+    REPUTATION_TOTALS.with(|r| r.borrow_mut().insert(user_id, reputation));
+
+    let sum_ratings: u32 = stats
+        .star_distribution
+        .iter()
+        .enumerate()
+        .map(|(i, count)| (i as u32 + 1) * count)
+        .sum();
+    stats.average_rating = sum_ratings as f64 / stats.total_ratings as f64;
+    stats.overall_score = overall_score(&stats.category_ratings, &rating.rated_role);
+    stats.last_updated = now;
+
+    RATING_STATS.with(|s| s.borrow_mut().insert(user_id, stats));
 }
 
-impl Storable for UserRatingStats {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_json::to_vec(self).unwrap())
-    }
+// Rolls category averages up into one score using the role's category weights. Categories
+// the user hasn't been rated on yet are left out rather than counted as zero.
+fn overall_score(category_ratings: &[(RatingCategory, f64)], role: &UserRole) -> f64 {
+    let (weighted_sum, total_weight) =
+        role_criteria(role)
+            .iter()
+            .fold(
+                (0.0, 0.0),
+                |(sum, total), (criterion, weight)| match category_ratings
+                    .iter()
+                    .find(|(category, _)| category == criterion)
+                {
+                    Some((_, average)) => (sum + weight * average, total + weight),
+                    None => (sum, total),
+                },
+            );
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_json::from_slice(&bytes).unwrap()
+    if total_weight > 0.0 {
+        weighted_sum / total_weight
+    } else {
+        0.0
     }
+}
 
-    const BOUND: ic_stable_structures::storable::Bound =
-        ic_stable_structures::storable::Bound::Bounded {
-            max_size: 2048,
-            is_fixed_size: false,
-        };
+fn rating_weight(rating: &Rating, now: u64) -> f64 {
+    let base = if rating.is_verified {
+        VERIFIED_WEIGHT
+    } else {
+        1.0
+    };
+    base * decay_factor(now.saturating_sub(rating.created_at))
 }
 
-impl Storable for RatingReport {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_json::to_vec(self).unwrap())
+// Stored scores are only as fresh as the user's last rating, so reads decay them to now.
+fn with_current_reputation(mut stats: UserRatingStats) -> UserRatingStats {
+    if let Some(reputation) = REPUTATION_TOTALS.with(|r| r.borrow().get(&stats.user_id)) {
+        stats.reputation_score = reputation.decayed_to(time()).score();
     }
+    stats
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_json::from_slice(&bytes).unwrap()
+#[update]
+async fn rebuild_rating_stats() -> Result<(), String> {
+    require_moderator(msg_caller()).await?;
+
+    if rebuild_cursor().is_some() {
+        return Err("A rebuild is already in progress".to_string());
     }
-    const BOUND: ic_stable_structures::storable::Bound =
-        ic_stable_structures::storable::Bound::Bounded {
-            max_size: 2048,
-            is_fixed_size: false,
-        };
+
+    start_rebuild();
+    Ok(())
 }
 
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
-        MemoryManager::init(DefaultMemoryImpl::default())
-    );
+fn start_rebuild() {
+    RATING_STATS.with(|s| s.borrow_mut().clear_new());
+    CATEGORY_TOTALS.with(|c| c.borrow_mut().clear_new());
+    REPUTATION_TOTALS.with(|r| r.borrow_mut().clear_new());
+    set_rebuild_cursor(Some(None));
 
-    static RATINGS: RefCell<StableBTreeMap<String, Rating, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0)))
-        )
-    );
+    ic_cdk_timers::set_timer(Duration::ZERO, rebuild_next_batch);
+}
 
-    static RATING_STATS: RefCell<StableBTreeMap<Principal, UserRatingStats, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
-        )
-    );
+// Processes one batch of ratings per message so the rebuild stays within the instruction
+// limit however many ratings there are.
+fn rebuild_next_batch() {
+    let Some(cursor) = rebuild_cursor() else {
+        return;
+    };
 
-    static RATING_REPORTS: RefCell<StableBTreeMap<String, RatingReport, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
-        )
-    );
+    match rebuild_batch(cursor, time()) {
+        Some(last) => {
+            set_rebuild_cursor(Some(Some(last)));
+            ic_cdk_timers::set_timer(Duration::ZERO, rebuild_next_batch);
+        }
+        None => set_rebuild_cursor(None),
+    }
 }
 
-fn generate_id() -> String {
-    let timestamp = time();
-    let caller = ic_cdk::caller();
-    format!("{}-{}", timestamp, caller.to_text())
+// Adds the next REBUILD_BATCH_SIZE ratings after `cursor` to the stats. Returns the last
+// rating id processed if there may be more.
+fn rebuild_batch(cursor: Option<String>, now: u64) -> Option<String> {
+    let start = match cursor {
+        Some(id) => Bound::Excluded(id),
+        None => Bound::Unbounded,
+    };
+    let batch: Vec<Rating> = RATINGS.with(|r| {
+        r.borrow()
+            .range((start, Bound::Unbounded))
+            .take(REBUILD_BATCH_SIZE)
+            .map(|(_, rating)| rating)
+            .collect()
+    });
+
+    for rating in batch.iter().filter(|rating| is_counted(rating)) {
+        apply_to_stats(rating, 1, now);
+    }
+
+    match batch.last() {
+        Some(last) if batch.len() == REBUILD_BATCH_SIZE => Some(last.id.clone()),
+        _ => None,
+    }
 }
 
 #[update]
-fn submit_rating(
-    rated_user_id: Principal,
-    product_id: Option<String>,
-    transaction_id: Option<String>,
-    rating: u8,
-    review: String,
-    category: String,
-) -> Result<Rating, String> {
-    let caller = ic_cdk::caller();
-    let current_time = time();
+async fn verify_rating(rating_id: String) -> Result<Rating, String> {
+    let caller = msg_caller();
+    require_moderator(caller).await?;
 
-    if rating < 1 || rating > 5 {
-        return Err("Rating must be between 1 and 5".to_string());
+    let mut rating = RATINGS.with(|r| {
+        r.borrow()
+            .get(&rating_id)
+            .ok_or("Rating not found".to_string())
+    })?;
+
+    // Verified ratings carry more reputation weight, so re-apply the rating with its new weight.
+    let reweigh = !rating.is_verified && is_counted(&rating);
+    if reweigh {
+        remove_from_stats(&rating);
+    }
+    rating.is_verified = true;
+    if reweigh {
+        add_to_stats(&rating);
     }
+    RATINGS.with(|r| r.borrow_mut().insert(rating_id.clone(), rating.clone()));
 
-    if caller == rated_user_id {
-        return Err("Cannot rate yourself".to_string());
+    record_moderation(caller, AuditAction::VerifyRating, rating_id, None);
+
+    Ok(rating)
+}
+
+#[update]
+async fn resolve_report(
+    report_id: String,
+    action: ModerationAction,
+) -> Result<RatingReport, String> {
+    let caller = msg_caller();
+    require_moderator(caller).await?;
+
+    let report = RATING_REPORTS.with(|r| {
+        r.borrow()
+            .get(&report_id)
+            .ok_or("Report not found".to_string())
+    })?;
+
+    if report.status != "pending" {
+        return Err("Report has already been resolved".to_string());
     }
 
-    let rating_id = generate_id();
-    let rating_obj = Rating {
-        id: rating_id.clone(),
-        rater_id: caller,
-        rated_user_id,
-        product_id,
-        transaction_id,
-        rating,
-        review,
-        category,
-        created_at: current_time,
-        is_verified: false,
+    let rating = RATINGS.with(|r| r.borrow().get(&report.rating_id));
+
+    // Hiding or deleting the rating settles every pending report against it, not just this one.
+    let (status, settled_reports) = match (&action, rating) {
+        (ModerationAction::Dismiss, _) => ("dismissed", vec![report.id.clone()]),
+        (_, None) => return Err("Rating not found".to_string()),
+        (ModerationAction::HideRating, Some(mut rating)) => {
+            if is_counted(&rating) {
+                remove_from_stats(&rating);
+            }
+            rating.is_hidden = true;
+            RATINGS.with(|r| r.borrow_mut().insert(rating.id.clone(), rating.clone()));
+            ("resolved", pending_reports_for(&rating.id))
+        }
+        (ModerationAction::DeleteRating, Some(rating)) => {
+            delete_rating(&rating);
+            ("resolved", pending_reports_for(&rating.id))
+        }
     };
 
-    RATINGS.with(|r| r.borrow_mut().insert(rating_id.clone(), rating_obj.clone()));
+    for id in settled_reports {
+        RATING_REPORTS.with(|r| {
+            let mut reports = r.borrow_mut();
+            if let Some(mut settled) = reports.get(&id) {
+                settled.status = status.to_string();
+                reports.insert(id, settled);
+            }
+        });
+    }
 
-    // Update rating stats
-    update_rating_stats(rated_user_id);
+    record_moderation(
+        caller,
+        AuditAction::ResolveReport(action),
+        report.rating_id.clone(),
+        Some(report_id.clone()),
+    );
 
-    Ok(rating_obj)
+    RATING_REPORTS.with(|r| {
+        r.borrow()
+            .get(&report_id)
+            .ok_or("Report not found".to_string())
+    })
 }
 
-fn update_rating_stats(user_id: Principal) {
-    let current_time = time();
+// Removes a moderated rating. Its TRANSFER_RATINGS entry is kept as a tombstone so the rater
+// can't rate the same transfer again once the rating is gone.
+fn delete_rating(rating: &Rating) {
+    RATINGS.with(|r| r.borrow_mut().remove(&rating.id));
+    let pair_key = format!(
+        "{}{}",
+        pair_prefix(rating.rater_id, rating.rated_user_id),
+        rating.id
+    );
+    PAIR_RATINGS.with(|p| p.borrow_mut().remove(&pair_key));
+    RECEIVED_RATINGS.with(|r| r.borrow_mut().remove(&received_key(rating)));
+    if is_counted(rating) {
+        remove_from_stats(rating);
+    }
+}
 
-    // Get all ratings for this user
-    let user_ratings: Vec<Rating> = RATINGS.with(|r| {
+fn pending_reports_for(rating_id: &str) -> Vec<String> {
+    RATING_REPORTS.with(|r| {
         r.borrow()
             .iter()
-            .filter(|(_, rating)| rating.rated_user_id == user_id)
-            .map(|(_, rating)| rating)
+            .filter(|(_, report)| report.rating_id == rating_id && report.status == "pending")
+            .map(|(id, _)| id)
             .collect()
-    });
+    })
+}
 
-    if user_ratings.is_empty() {
-        return;
+#[query(composite = true)]
+async fn get_moderation_log() -> Result<Vec<ModerationAuditEntry>, String> {
+    require_moderator(msg_caller()).await?;
+
+    Ok(MODERATION_LOG.with(|l| l.borrow().iter().map(|(_, entry)| entry).collect()))
+}
+
+fn get_own_rating(rating_id: &String, caller: Principal) -> Result<Rating, String> {
+    let rating = RATINGS.with(|r| {
+        r.borrow()
+            .get(rating_id)
+            .ok_or("Rating not found".to_string())
+    })?;
+
+    if rating.rater_id != caller {
+        return Err("Not authorized to change this rating".to_string());
     }
+    if rating.retracted_at.is_some() {
+        return Err("Rating has been retracted".to_string());
+    }
+    if time() > rating.created_at + EDIT_WINDOW {
+        return Err("The edit window for this rating has closed".to_string());
+    }
+
+    Ok(rating)
+}
 
-    let total_ratings = user_ratings.len() as u32;
-    let sum_ratings: u32 = user_ratings.iter().map(|r| r.rating as u32).sum();
-    let average_rating = sum_ratings as f64 / total_ratings as f64;
+#[update]
+async fn edit_rating(rating_id: String, rating: u8, review: String) -> Result<Rating, String> {
+    let caller = msg_caller();
+    validate_rating(rating, &review)?;
+    require_active(caller).await?;
 
-    // Calculate star distribution
-    let mut star_distribution = vec![0; 5];
-    for rating in &user_ratings {
-        star_distribution[(rating.rating - 1) as usize] += 1;
+    let mut rating_obj = get_own_rating(&rating_id, caller)?;
+    if rating_obj.edit_history.len() >= MAX_RATING_EDITS {
+        return Err(format!(
+            "A rating can be edited at most {} times",
+            MAX_RATING_EDITS
+        ));
     }
 
-    // Calculate category ratings
-    let mut category_sums: std::collections::HashMap<String, (u32, u32)> =
-        std::collections::HashMap::new();
-    for rating in &user_ratings {
-        let entry = category_sums
-            .entry(rating.category.clone())
-            .or_insert((0, 0));
-        entry.0 += rating.rating as u32;
-        entry.1 += 1;
+    let counted = is_counted(&rating_obj);
+    if counted {
+        remove_from_stats(&rating_obj);
     }
 
-    let category_ratings: Vec<(String, f64)> = category_sums
-        .into_iter()
-        .map(|(category, (sum, count))| (category, sum as f64 / count as f64))
-        .collect();
+    let previous_rating = std::mem::replace(&mut rating_obj.rating, rating);
+    let previous_review = std::mem::replace(&mut rating_obj.review, review);
+    rating_obj.edit_history.push(RatingEdit {
+        rating: previous_rating,
+        review: previous_review,
+        replaced_at: time(),
+    });
 
-    let stats = UserRatingStats {
-        user_id,
-        total_ratings,
-        average_rating,
-        star_distribution,
-        category_ratings,
-        last_updated: current_time,
-    };
+    if counted {
+        add_to_stats(&rating_obj);
+    }
 
-    RATING_STATS.with(|s| s.borrow_mut().insert(user_id, stats));
+    RATINGS.with(|r| r.borrow_mut().insert(rating_id, rating_obj.clone()));
+
+    Ok(rating_obj)
 }
 
 #[update]
-fn verify_rating(rating_id: String) -> Result<Rating, String> {
-    let caller = ic_cdk::caller();
+async fn retract_rating(rating_id: String) -> Result<Rating, String> {
+    let caller = msg_caller();
+    require_active(caller).await?;
 
-    // Note: In a real implementation, you'd check if the caller is an admin
-    // For now, we'll allow anyone to verify ratings
+    let mut rating = get_own_rating(&rating_id, caller)?;
+    if is_counted(&rating) {
+        remove_from_stats(&rating);
+    }
+    rating.retracted_at = Some(time());
+
+    RATINGS.with(|r| r.borrow_mut().insert(rating_id, rating.clone()));
+
+    Ok(rating)
+}
+
+#[update]
+async fn respond_to_rating(rating_id: String, text: String) -> Result<Rating, String> {
+    let caller = msg_caller();
+    require_active(caller).await?;
 
     let mut rating = RATINGS.with(|r| {
         r.borrow()
@@ -230,19 +1489,36 @@ fn verify_rating(rating_id: String) -> Result<Rating, String> {
             .ok_or("Rating not found".to_string())
     })?;
 
-    rating.is_verified = true;
+    if rating.rated_user_id != caller {
+        return Err("Only the rated user can respond to this rating".to_string());
+    }
+    if rating.response.is_some() {
+        return Err("This rating already has a response".to_string());
+    }
+    if text.chars().count() > MAX_REVIEW_LENGTH {
+        return Err(format!(
+            "Response must be at most {} characters",
+            MAX_REVIEW_LENGTH
+        ));
+    }
+
+    rating.response = Some(RatingResponse {
+        text,
+        created_at: time(),
+    });
     RATINGS.with(|r| r.borrow_mut().insert(rating_id, rating.clone()));
 
     Ok(rating)
 }
 
 #[update]
-fn report_rating(
+async fn report_rating(
     rating_id: String,
     reason: String,
     description: String,
 ) -> Result<RatingReport, String> {
-    let caller = ic_cdk::caller();
+    let caller = msg_caller();
+    require_active(caller).await?;
     let current_time = time();
 
     // Check if rating exists
@@ -283,6 +1559,7 @@ fn get_user_ratings(user_id: Principal) -> Vec<Rating> {
         r.borrow()
             .iter()
             .filter(|(_, rating)| rating.rated_user_id == user_id)
+            .filter(|(_, rating)| is_counted(rating))
             .map(|(_, rating)| rating)
             .collect()
     })
@@ -294,6 +1571,7 @@ fn get_ratings_by_rater(rater_id: Principal) -> Vec<Rating> {
         r.borrow()
             .iter()
             .filter(|(_, rating)| rating.rater_id == rater_id)
+            .filter(|(_, rating)| is_counted(rating))
             .map(|(_, rating)| rating)
             .collect()
     })
@@ -301,21 +1579,44 @@ fn get_ratings_by_rater(rater_id: Principal) -> Vec<Rating> {
 
 #[query]
 fn get_user_rating_stats(user_id: Principal) -> Result<UserRatingStats, String> {
-    RATING_STATS.with(|s| {
-        s.borrow()
-            .get(&user_id)
-            .ok_or("Rating stats not found".to_string())
+    RATING_STATS
+        .with(|s| s.borrow().get(&user_id))
+        .map(with_current_reputation)
+        .ok_or("Rating stats not found".to_string())
+}
+
+// Current reputation for each of `users`, in order. Users with no ratings have the prior
+// mean. Used by user_management_backend to filter its directory search.
+#[query]
+fn get_reputation_scores(users: Vec<Principal>) -> Vec<(Principal, f64)> {
+    let now = time();
+    REPUTATION_TOTALS.with(|r| {
+        let totals = r.borrow();
+        users
+            .into_iter()
+            .map(|user| {
+                let score = totals
+                    .get(&user)
+                    .map_or(PRIOR_MEAN, |reputation| reputation.decayed_to(now).score());
+                (user, score)
+            })
+            .collect()
     })
 }
 
 #[query]
 fn get_top_rated_users(limit: u32) -> Vec<UserRatingStats> {
-    let mut stats: Vec<UserRatingStats> =
-        RATING_STATS.with(|s| s.borrow().iter().map(|(_, stats)| stats).collect());
+    let mut stats: Vec<UserRatingStats> = RATING_STATS.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, stats)| stats.total_ratings >= MIN_RATINGS_FOR_LEADERBOARD)
+            .map(|(_, stats)| with_current_reputation(stats))
+            .collect()
+    });
 
     stats.sort_by(|a, b| {
-        b.average_rating
-            .partial_cmp(&a.average_rating)
+        b.reputation_score
+            .partial_cmp(&a.reputation_score)
             .unwrap_or(std::cmp::Ordering::Equal)
     });
     stats.truncate(limit as usize);
@@ -324,16 +1625,43 @@ fn get_top_rated_users(limit: u32) -> Vec<UserRatingStats> {
 }
 
 #[query]
-fn get_ratings_by_category(category: String) -> Vec<Rating> {
+fn get_ratings_by_category(category: RatingCategory) -> Vec<Rating> {
     RATINGS.with(|r| {
         r.borrow()
             .iter()
             .filter(|(_, rating)| rating.category == category)
+            .filter(|(_, rating)| is_counted(rating))
             .map(|(_, rating)| rating)
             .collect()
     })
 }
 
+// Called by supply_chain_backend when a dispute closes. Closures are keyed by dispute id, so a
+// report that is retried after a partial failure overwrites the earlier one.
+#[update]
+fn record_dispute_closure(closure: DisputeClosure) -> Result<(), String> {
+    let supply_chain_canister = CANISTER_CONFIG.with(|c| c.borrow().get().supply_chain_canister);
+    if supply_chain_canister != Some(msg_caller()) {
+        return Err("Only the supply chain canister can report disputes".to_string());
+    }
+
+    DISPUTE_CLOSURES.with(|d| d.borrow_mut().insert(closure.dispute_id.clone(), closure));
+
+    Ok(())
+}
+
+// Closed disputes the user was a party to, for weighing alongside their ratings.
+#[query]
+fn get_dispute_history(user_id: Principal) -> Vec<DisputeClosure> {
+    DISPUTE_CLOSURES.with(|d| {
+        d.borrow()
+            .iter()
+            .filter(|(_, closure)| closure.claimant == user_id || closure.respondent == user_id)
+            .map(|(_, closure)| closure)
+            .collect()
+    })
+}
+
 #[query]
 fn get_pending_reports() -> Vec<RatingReport> {
     RATING_REPORTS.with(|r| {
@@ -346,3 +1674,239 @@ fn get_pending_reports() -> Vec<RatingReport> {
 }
 
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rating(id: &str, rater: Principal, rated_user_id: Principal, stars: u8) -> Rating {
+        Rating {
+            id: id.to_string(),
+            rater_id: rater,
+            rated_user_id,
+            product_id: None,
+            transaction_id: None,
+            rating: stars,
+            review: String::new(),
+            category: RatingCategory::Overall,
+            rated_role: UserRole::Supplier,
+            created_at: 0,
+            is_verified: false,
+            is_hidden: false,
+            retracted_at: None,
+            edit_history: vec![],
+            response: None,
+        }
+    }
+
+    #[test]
+    fn deleted_rating_still_blocks_rerating_the_transfer() {
+        let rater = Principal::from_slice(&[1]);
+        let rated = Principal::from_slice(&[2]);
+        let mut deleted = rating("r1", rater, rated, 1);
+        deleted.transaction_id = Some("t1".to_string());
+        // Hidden first, as moderators usually do, so the stats are already settled.
+        deleted.is_hidden = true;
+        let key = transfer_rating_key(rater, "t1", &deleted.category);
+        RATINGS.with(|r| r.borrow_mut().insert(deleted.id.clone(), deleted.clone()));
+        TRANSFER_RATINGS.with(|t| t.borrow_mut().insert(key.clone(), deleted.id.clone()));
+
+        delete_rating(&deleted);
+
+        assert!(RATINGS.with(|r| r.borrow().get(&deleted.id).is_none()));
+        assert!(TRANSFER_RATINGS.with(|t| t.borrow().contains_key(&key)));
+    }
+
+    #[test]
+    fn legacy_category_names_map_to_typed_categories() {
+        assert_eq!(legacy_category("quality"), RatingCategory::Quality);
+        assert_eq!(legacy_category("delivery"), RatingCategory::Punctuality);
+        assert_eq!(
+            legacy_category("communication"),
+            RatingCategory::Communication
+        );
+        assert_eq!(legacy_category("general"), RatingCategory::Overall);
+    }
+
+    #[test]
+    fn legacy_rating_gets_a_typed_category_and_role() {
+        let current = rating(
+            "r1",
+            Principal::from_slice(&[1]),
+            Principal::from_slice(&[2]),
+            4,
+        );
+        let mut legacy = serde_json::to_value(&current).unwrap();
+        legacy["category"] = serde_json::json!("delivery");
+        legacy.as_object_mut().unwrap().remove("rated_role");
+        legacy.as_object_mut().unwrap().remove("edit_history");
+
+        let migrated = Rating::from_bytes(Cow::Owned(serde_json::to_vec(&legacy).unwrap()));
+
+        assert_eq!(migrated.category, RatingCategory::Punctuality);
+        assert!(matches!(migrated.rated_role, UserRole::Admin));
+        assert!(migrated.edit_history.is_empty());
+        assert_eq!(migrated.rating, 4);
+    }
+
+    #[test]
+    fn legacy_stats_get_typed_categories_and_the_prior_score() {
+        let legacy = serde_json::json!({
+            "user_id": Principal::from_slice(&[2]),
+            "total_ratings": 1,
+            "average_rating": 4.0,
+            "star_distribution": [0, 0, 0, 1, 0],
+            "category_ratings": [["quality", 4.0]],
+            "last_updated": 0,
+        });
+
+        let migrated =
+            UserRatingStats::from_bytes(Cow::Owned(serde_json::to_vec(&legacy).unwrap()));
+
+        assert_eq!(
+            migrated.category_ratings,
+            vec![(RatingCategory::Quality, 4.0)]
+        );
+        assert_eq!(migrated.reputation_score, PRIOR_MEAN);
+        assert_eq!(migrated.overall_score, 0.0);
+    }
+
+    #[test]
+    fn rating_weight_halves_every_half_life() {
+        assert_eq!(decay_factor(0), 1.0);
+        assert!((decay_factor(REPUTATION_HALF_LIFE) - 0.5).abs() < 1e-12);
+        assert!((decay_factor(2 * REPUTATION_HALF_LIFE) - 0.25).abs() < 1e-12);
+    }
+
+    #[test]
+    fn decayed_reputation_moves_back_towards_the_prior() {
+        let totals = ReputationTotals {
+            weight: 10.0,
+            weighted_sum: 50.0,
+            updated_at: 0,
+        };
+
+        let decayed = totals.decayed_to(REPUTATION_HALF_LIFE);
+
+        assert!((decayed.weight - 5.0).abs() < 1e-9);
+        assert!((decayed.weighted_sum - 25.0).abs() < 1e-9);
+        assert_eq!(decayed.updated_at, REPUTATION_HALF_LIFE);
+        assert!(decayed.score() < totals.score());
+        assert!(decayed.score() > PRIOR_MEAN);
+        assert_eq!(ReputationTotals::default().score(), PRIOR_MEAN);
+    }
+
+    #[test]
+    fn rebuild_counts_only_visible_ratings() {
+        let rater = Principal::from_slice(&[1]);
+        let rated = Principal::from_slice(&[2]);
+        let mut hidden = rating("r3", rater, rated, 1);
+        hidden.is_hidden = true;
+        for stored in [
+            rating("r1", rater, rated, 5),
+            rating("r2", rater, rated, 3),
+            hidden,
+        ] {
+            RATINGS.with(|r| r.borrow_mut().insert(stored.id.clone(), stored));
+        }
+
+        assert_eq!(rebuild_batch(None, 0), None);
+
+        let stats = RATING_STATS.with(|s| s.borrow().get(&rated)).unwrap();
+        assert_eq!(stats.total_ratings, 2);
+        assert_eq!(stats.average_rating, 4.0);
+        assert_eq!(stats.star_distribution, vec![0, 0, 1, 0, 1]);
+        assert_eq!(stats.category_ratings, vec![(RatingCategory::Overall, 4.0)]);
+    }
+
+    #[test]
+    fn ratings_past_the_rebuild_cursor_are_left_to_the_rebuild() {
+        set_rebuild_cursor(Some(Some("r2".to_string())));
+
+        assert!(!pending_rebuild(&"r1".to_string()));
+        assert!(pending_rebuild(&"r3".to_string()));
+
+        set_rebuild_cursor(None);
+        assert!(!pending_rebuild(&"r3".to_string()));
+    }
+
+    fn rated(id: &str, rater: Principal, rated_user_id: Principal, stars: u8, at: u64) -> Rating {
+        let mut stored = rating(id, rater, rated_user_id, stars);
+        stored.created_at = at;
+        RATINGS.with(|r| r.borrow_mut().insert(stored.id.clone(), stored.clone()));
+        PAIR_RATINGS.with(|p| {
+            p.borrow_mut().insert(
+                format!("{}{}", pair_prefix(rater, rated_user_id), stored.id),
+                stored.id.clone(),
+            )
+        });
+        stored
+    }
+
+    fn ids(chain: Option<Vec<Rating>>) -> Option<Vec<String>> {
+        chain.map(|chain| chain.into_iter().map(|rating| rating.id).collect())
+    }
+
+    #[test]
+    fn reciprocal_ratings_form_a_ring() {
+        let (a, b) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        rated("ab", a, b, 5, 0);
+
+        let closing = rating("ba", b, a, 5);
+
+        assert_eq!(
+            ids(find_rating_ring(&closing)),
+            Some(vec!["ab".to_string()])
+        );
+    }
+
+    #[test]
+    fn ring_through_a_third_user_is_returned_in_order() {
+        let (a, b, c) = (
+            Principal::from_slice(&[1]),
+            Principal::from_slice(&[2]),
+            Principal::from_slice(&[3]),
+        );
+        rated("ab", a, b, 5, 0);
+        rated("bc", b, c, 4, 0);
+        rated("bx", b, Principal::from_slice(&[9]), 5, 0);
+
+        let closing = rating("ca", c, a, 5);
+
+        assert_eq!(
+            ids(find_rating_ring(&closing)),
+            Some(vec!["ab".to_string(), "bc".to_string()])
+        );
+    }
+
+    #[test]
+    fn low_or_stale_ratings_do_not_close_a_ring() {
+        let (a, b, c) = (
+            Principal::from_slice(&[1]),
+            Principal::from_slice(&[2]),
+            Principal::from_slice(&[3]),
+        );
+        rated("ab", a, b, 5, RECIPROCAL_WINDOW);
+        rated("bc", b, c, RECIPROCAL_MIN_STARS - 1, RECIPROCAL_WINDOW);
+        rated("ac", a, c, 5, 0);
+
+        let mut closing = rating("ca", c, a, 5);
+        closing.created_at = RECIPROCAL_WINDOW;
+
+        assert_eq!(ids(find_rating_ring(&closing)), None);
+    }
+
+    #[test]
+    fn rings_longer_than_the_limit_are_not_reported() {
+        let users: Vec<Principal> = (1..=RING_MAX_LENGTH as u8 + 1)
+            .map(|n| Principal::from_slice(&[n]))
+            .collect();
+        for pair in users.windows(2) {
+            rated(&format!("{}", pair[0]), pair[0], pair[1], 5, 0);
+        }
+
+        let closing = rating("closing", users[RING_MAX_LENGTH], users[0], 5);
+
+        assert_eq!(ids(find_rating_ring(&closing)), None);
+    }
+}
@@ -1,26 +1,178 @@
 use candid::{CandidType, Deserialize, Principal};
-use ic_cdk::api::time;
+use ic_cdk::api::{canister_self, msg_caller, time};
+use ic_cdk::call::Call;
 use ic_cdk_macros::*;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
 use serde::Serialize;
 use std::borrow::Cow;
 use std::cell::RefCell;
+use std::time::Duration;
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+const NANOS_PER_DAY: u64 = 24 * NANOS_PER_HOUR;
+
+// How often the scheduler wakes up to look for due schedules.
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MAX_SCHEDULE_RUN_HISTORY: usize = 10;
+
+// Bodies larger than this are served through the streaming callback.
+const HTTP_CHUNK_SIZE: usize = 1024 * 1024;
+
+// Report data is serialized and split into chunks of this size so a report's body is not
+// limited by the bound on the report record itself.
+const REPORT_CHUNK_SIZE: usize = 64 * 1024;
+// Bound on a report record once its data has moved to REPORT_CHUNKS.
+const MAX_REPORT_RECORD_SIZE: u32 = 4096;
+const MAX_TITLE_LENGTH: usize = 200;
+const MAX_REPORT_SHARES: usize = 25;
+const RETENTION_PURGE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+// Reports examined per message by the retention purge, which reschedules itself until done.
+const RETENTION_PURGE_BATCH_SIZE: usize = 500;
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum ReportType {
+    SupplyChainOverview,
+    PerformanceReport,
+    TransactionSummary,
+    QualityMetrics,
+}
+
+// Report values are stored raw; formatting them for display is left to the client.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum ReportValue {
+    Count(u64),
+    Ratio(f64), // 0.0 - 1.0
+    Money {
+        amount: u64,
+        currency: String,
+    }, // amount in minor units (cents)
+    Duration(u64), // nanoseconds
+    Series(Vec<(u64, f64)>), // [(timestamp, value)]
+    Table {
+        columns: Vec<String>,
+        rows: Vec<(String, Vec<ReportValue>)>, // [(row label, one value per column)]
+    },
+}
+
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
 pub struct Report {
     pub id: String,
     pub title: String,
-    pub report_type: String,
+    pub report_type: ReportType,
     pub generated_by: Principal,
     pub created_at: u64,
-    pub data: Vec<(String, String)>,
+    pub data: Vec<(String, ReportValue)>,
     pub summary: String,
     pub period_start: u64,
     pub period_end: u64,
     pub is_public: bool,
+    #[serde(default)] // absent on reports stored before sharing existed
+    pub shared_with: Vec<Principal>,
+    pub archived_at: Option<u64>, // set once the body has been dropped by the retention policy
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum RetentionAction {
+    Delete,
+    Archive, // keep the report record but drop its data
+}
+
+// Principals of the other canisters this one calls, set by a controller after deployment.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct CanisterConfig {
+    pub user_management_canister: Option<Principal>,
+    pub supply_chain_canister: Option<Principal>,
+}
+
+// Mirrors of supply_chain_backend's dispute closure report.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum DisputeOutcome {
+    Upheld,
+    PartiallyUpheld,
+    Rejected,
+}
+
+// A dispute closed in supply_chain_backend. The outcome is None for withdrawn disputes.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct DisputeClosure {
+    pub dispute_id: String,
+    pub claimant: Principal,
+    pub respondent: Principal,
+    pub outcome: Option<DisputeOutcome>,
+    pub opened_at: u64,
+    pub closed_at: u64,
+}
+
+// Mirrors of user_management_backend's account types.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+enum AccountStatus {
+    Active,
+    Suspended,
+    Deactivated,
+    Erased,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct User {
+    status: AccountStatus,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct RetentionPolicy {
+    pub max_report_age: Option<u64>, // nanoseconds, None keeps reports indefinitely
+    pub max_reports_per_user: u32,
+    pub action: RetentionAction,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            max_report_age: Some(365 * NANOS_PER_DAY),
+            max_reports_per_user: 200,
+            action: RetentionAction::Archive,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum Cadence {
+    Daily,
+    Weekly,
+    Monthly, // every 30 days
+}
+
+impl Cadence {
+    fn interval(&self) -> u64 {
+        match self {
+            Cadence::Daily => NANOS_PER_DAY,
+            Cadence::Weekly => 7 * NANOS_PER_DAY,
+            Cadence::Monthly => 30 * NANOS_PER_DAY,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ScheduleRun {
+    pub ran_at: u64,
+    pub report_id: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ReportSchedule {
+    pub id: String,
+    pub owner: Principal,
+    pub report_type: ReportType,
+    pub cadence: Cadence,
+    pub is_public: bool,
+    pub created_at: u64,
+    pub next_run_at: u64,
+    pub last_run_at: Option<u64>,
+    pub last_error: Option<String>,
+    pub run_history: Vec<ScheduleRun>, // most recent last, capped at MAX_SCHEDULE_RUN_HISTORY
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
@@ -47,23 +199,128 @@ pub struct SupplyChainAnalytics {
     pub generated_at: u64,
 }
 
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub streaming_strategy: Option<StreamingStrategy>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StreamingCallbackToken {
+    pub report_id: String,
+    pub format: String,
+    pub index: u32,
+}
+
+candid::define_function!(pub StreamingCallbackFunction : (StreamingCallbackToken) -> (StreamingCallbackHttpResponse) query);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum StreamingStrategy {
+    Callback {
+        callback: StreamingCallbackFunction,
+        token: StreamingCallbackToken,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StreamingCallbackHttpResponse {
+    pub body: Vec<u8>,
+    pub token: Option<StreamingCallbackToken>,
+}
+
+// Reports stored before report types and values were typed hold strings for both, as
+// written by the original generators.
+fn legacy_report_type(name: &str) -> ReportType {
+    match name {
+        "PERFORMANCE_REPORT" => ReportType::PerformanceReport,
+        "TRANSACTION_SUMMARY" => ReportType::TransactionSummary,
+        "QUALITY_METRICS" => ReportType::QualityMetrics,
+        // Unknown types were accepted too, and produced an empty report.
+        _ => ReportType::SupplyChainOverview,
+    }
+}
+
+fn legacy_report_value(value: &str) -> Option<ReportValue> {
+    if let Some(percent) = value.strip_suffix('%') {
+        return percent
+            .parse::<f64>()
+            .ok()
+            .map(|p| ReportValue::Ratio(p / 100.0));
+    }
+    if let Some(days) = value.strip_suffix(" days") {
+        let days = days.parse::<f64>().ok()?;
+        return Some(ReportValue::Duration((days * NANOS_PER_DAY as f64) as u64));
+    }
+    if let Some(dollars) = value.strip_prefix('$') {
+        let dollars = dollars.replace(',', "").parse::<f64>().ok()?;
+        return Some(ReportValue::Money {
+            amount: (dollars * 100.0).round() as u64,
+            currency: "USD".to_string(),
+        });
+    }
+    if let Some((score, scale)) = value.split_once('/') {
+        let score = score.parse::<f64>().ok()?;
+        let scale = scale.parse::<f64>().ok()?;
+        return (scale > 0.0).then(|| ReportValue::Ratio(score / scale));
+    }
+    value.parse::<u64>().ok().map(ReportValue::Count)
+}
+
+fn migrate_legacy_report(bytes: &[u8]) -> Report {
+    let mut report: serde_json::Value = serde_json::from_slice(bytes).unwrap();
+
+    if let Some(name) = report["report_type"].as_str() {
+        report["report_type"] = serde_json::to_value(legacy_report_type(name)).unwrap();
+    }
+
+    if let Some(data) = report["data"].as_array() {
+        // Typed entries are kept as they are. String values none of the original generators
+        // could have produced are dropped.
+        let data: Vec<serde_json::Value> = data
+            .iter()
+            .filter_map(
+                |entry| match entry.get(1).and_then(|value| value.as_str()) {
+                    Some(value) => {
+                        let value = legacy_report_value(value)?;
+                        Some(serde_json::json!([entry.get(0)?, value]))
+                    }
+                    None => Some(entry.clone()),
+                },
+            )
+            .collect();
+        report["data"] = serde_json::Value::Array(data);
+    }
+
+    serde_json::from_value(report).unwrap()
+}
+
 impl Storable for Report {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(serde_json::to_vec(self).unwrap())
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_json::from_slice(&bytes).unwrap()
+        serde_json::from_slice(&bytes).unwrap_or_else(|_| migrate_legacy_report(&bytes))
     }
     const BOUND: ic_stable_structures::storable::Bound =
         ic_stable_structures::storable::Bound::Bounded {
-            max_size: 2048,
+            max_size: MAX_REPORT_RECORD_SIZE,
             is_fixed_size: false,
         };
 }
 
 impl Storable for PerformanceMetrics {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(serde_json::to_vec(self).unwrap())
     }
 
@@ -78,7 +335,7 @@ impl Storable for PerformanceMetrics {
 }
 
 impl Storable for SupplyChainAnalytics {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(serde_json::to_vec(self).unwrap())
     }
 
@@ -92,6 +349,68 @@ impl Storable for SupplyChainAnalytics {
         };
 }
 
+impl Storable for ReportSchedule {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap()
+    }
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Bounded {
+            max_size: 4096,
+            is_fixed_size: false,
+        };
+}
+
+impl Storable for DisputeClosure {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Bounded {
+            max_size: 512,
+            is_fixed_size: false,
+        };
+}
+
+impl Storable for CanisterConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Bounded {
+            max_size: 512,
+            is_fixed_size: false,
+        };
+}
+
+impl Storable for RetentionPolicy {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap()
+    }
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Bounded {
+            max_size: 256,
+            is_fixed_size: false,
+        };
+}
+
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
         MemoryManager::init(DefaultMemoryImpl::default())
@@ -114,65 +433,540 @@ thread_local! {
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
         )
     );
+
+    static SCHEDULES: RefCell<StableBTreeMap<String, ReportSchedule, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+        )
+    );
+
+    // Serialized report data, keyed by "{report_id}/{chunk_index}". REPORTS only holds the
+    // report records with their data removed.
+    static REPORT_CHUNKS: RefCell<StableBTreeMap<String, Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
+        )
+    );
+
+    static RETENTION_POLICY: RefCell<StableCell<RetentionPolicy, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))),
+            RetentionPolicy::default(),
+        )
+        .expect("Failed to initialize retention policy")
+    );
+
+    static CANISTER_CONFIG: RefCell<StableCell<CanisterConfig, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))),
+            CanisterConfig::default(),
+        )
+        .expect("Failed to initialize canister config")
+    );
+
+    static DISPUTE_CLOSURES: RefCell<StableBTreeMap<String, DisputeClosure, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+        )
+    );
+
+    // Live (not archived) reports by owner, keyed by owner_report_key -> report id.
+    static OWNER_REPORTS: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+        )
+    );
+}
+
+// Timers are not preserved across upgrades, so the scheduler is re-armed here. The schedules
+// themselves live in stable memory.
+#[init]
+fn init() {
+    start_timers();
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    // Reports stored before the owner index existed are indexed once.
+    if OWNER_REPORTS.with(|o| o.borrow().is_empty()) {
+        let live: Vec<Report> = REPORTS.with(|r| {
+            r.borrow()
+                .iter()
+                .map(|(_, report)| report)
+                .filter(|report| report.archived_at.is_none())
+                .collect()
+        });
+        for report in live {
+            OWNER_REPORTS.with(|o| o.borrow_mut().insert(owner_report_key(&report), report.id));
+        }
+    }
+
+    start_timers();
+}
+
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(SCHEDULER_INTERVAL, || {
+        ic_cdk::futures::spawn(run_due_schedules())
+    });
+    ic_cdk_timers::set_timer_interval(RETENTION_PURGE_INTERVAL, purge_expired_reports);
+}
+
+fn chunk_key(report_id: &str, index: usize) -> String {
+    format!("{}/{:06}", report_id, index)
+}
+
+fn chunk_keys(report_id: &str) -> Vec<String> {
+    let prefix = format!("{}/", report_id);
+    REPORT_CHUNKS.with(|c| {
+        c.borrow()
+            .keys_range(prefix.clone()..)
+            .take_while(|key| key.starts_with(&prefix))
+            .collect()
+    })
+}
+
+fn delete_report_body(report_id: &str) {
+    for key in chunk_keys(report_id) {
+        REPORT_CHUNKS.with(|c| c.borrow_mut().remove(&key));
+    }
+}
+
+// Writes the report record and its chunked data. Callers that only change the record
+// (sharing, archiving) insert into REPORTS directly.
+// Fails instead of trapping when the record would exceed its bound, so that scheduled runs
+// can record the error.
+// The owner's reports sort oldest first under "{owner}|".
+fn owner_report_key(report: &Report) -> String {
+    format!(
+        "{}|{:020}|{}",
+        report.generated_by.to_text(),
+        report.created_at,
+        report.id
+    )
+}
+
+fn check_record_size(record: &Report) -> Result<(), String> {
+    if record.to_bytes().len() > MAX_REPORT_RECORD_SIZE as usize {
+        return Err("Report is too large to store".to_string());
+    }
+    Ok(())
+}
+
+// Rewrites an existing report record, e.g. after its sharing changed.
+fn save_report_record(record: &Report) -> Result<(), String> {
+    check_record_size(record)?;
+    REPORTS.with(|r| r.borrow_mut().insert(record.id.clone(), record.clone()));
+    Ok(())
+}
+
+fn store_report(report: &Report) -> Result<(), String> {
+    let body = serde_json::to_vec(&report.data).map_err(|e| e.to_string())?;
+    let record = Report {
+        data: vec![],
+        ..report.clone()
+    };
+    check_record_size(&record)?;
+
+    delete_report_body(&report.id);
+    for (index, chunk) in body.chunks(REPORT_CHUNK_SIZE).enumerate() {
+        REPORT_CHUNKS.with(|c| {
+            c.borrow_mut()
+                .insert(chunk_key(&report.id, index), chunk.to_vec())
+        });
+    }
+
+    REPORTS.with(|r| r.borrow_mut().insert(report.id.clone(), record));
+    if report.archived_at.is_none() {
+        OWNER_REPORTS.with(|o| {
+            o.borrow_mut()
+                .insert(owner_report_key(report), report.id.clone())
+        });
+    }
+
+    Ok(())
+}
+
+fn with_body(mut report: Report) -> Report {
+    let body: Vec<u8> = REPORT_CHUNKS.with(|c| {
+        let c = c.borrow();
+        chunk_keys(&report.id)
+            .iter()
+            .filter_map(|key| c.get(key))
+            .flatten()
+            .collect()
+    });
+
+    if !body.is_empty() {
+        report.data = serde_json::from_slice(&body).unwrap();
+    }
+    report
+}
+
+fn delete_report(report: &Report) {
+    delete_report_body(&report.id);
+    OWNER_REPORTS.with(|o| o.borrow_mut().remove(&owner_report_key(report)));
+    REPORTS.with(|r| r.borrow_mut().remove(&report.id));
+}
+
+fn apply_retention_action(mut report: Report, action: &RetentionAction, now: u64) {
+    match action {
+        RetentionAction::Delete => delete_report(&report),
+        RetentionAction::Archive => {
+            delete_report_body(&report.id);
+            OWNER_REPORTS.with(|o| o.borrow_mut().remove(&owner_report_key(&report)));
+            // Reports stored before chunking hold their data inline.
+            report.data = vec![];
+            report.archived_at = Some(now);
+            REPORTS.with(|r| r.borrow_mut().insert(report.id.clone(), report));
+        }
+    }
+}
+
+// Applies the retention action to a user's oldest live reports until they are within the
+// per-user limit.
+fn enforce_user_retention(owner: Principal, now: u64) {
+    let policy = RETENTION_POLICY.with(|p| p.borrow().get().clone());
+
+    let prefix = format!("{}|", owner.to_text());
+    let live: Vec<String> = OWNER_REPORTS.with(|o| {
+        o.borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, report_id)| report_id)
+            .collect()
+    });
+
+    let limit = policy.max_reports_per_user as usize;
+    let excess = live.len().saturating_sub(limit);
+    for report_id in live.into_iter().take(excess) {
+        if let Some(report) = REPORTS.with(|r| r.borrow().get(&report_id)) {
+            apply_retention_action(report, &policy.action, now);
+        }
+    }
+}
+
+fn purge_expired_reports() {
+    let policy = RETENTION_POLICY.with(|p| p.borrow().get().clone());
+    let Some(max_age) = policy.max_report_age else {
+        return;
+    };
+
+    purge_expired_from(time().saturating_sub(max_age), None);
+}
+
+fn purge_expired_from(cutoff: u64, after: Option<String>) {
+    if let Some(last) = purge_expired_batch(cutoff, after, time()) {
+        ic_cdk_timers::set_timer(Duration::ZERO, move || {
+            purge_expired_from(cutoff, Some(last))
+        });
+    }
+}
+
+// Applies the retention action to expired reports among the next RETENTION_PURGE_BATCH_SIZE
+// reports after `after`. Returns the last report id examined if there may be more.
+fn purge_expired_batch(cutoff: u64, after: Option<String>, now: u64) -> Option<String> {
+    let policy = RETENTION_POLICY.with(|p| p.borrow().get().clone());
+
+    let batch: Vec<Report> = REPORTS.with(|r| {
+        let r = r.borrow();
+        let start = match after {
+            Some(after) => std::ops::Bound::Excluded(after),
+            None => std::ops::Bound::Unbounded,
+        };
+        r.range((start, std::ops::Bound::Unbounded))
+            .take(RETENTION_PURGE_BATCH_SIZE)
+            .map(|(_, report)| report)
+            .collect()
+    });
+
+    let last = (batch.len() == RETENTION_PURGE_BATCH_SIZE)
+        .then(|| batch.last().map(|report| report.id.clone()))
+        .flatten();
+
+    for report in batch {
+        if report.created_at < cutoff && report.archived_at.is_none() {
+            apply_retention_action(report, &policy.action, now);
+        }
+    }
+
+    last
+}
+
+#[update]
+fn set_retention_policy(policy: RetentionPolicy) -> Result<RetentionPolicy, String> {
+    if !is_admin(&msg_caller()) {
+        return Err("Only admins can change the retention policy".to_string());
+    }
+
+    RETENTION_POLICY
+        .with(|p| p.borrow_mut().set(policy.clone()))
+        .map_err(|e| format!("{:?}", e))?;
+
+    Ok(policy)
+}
+
+#[query]
+fn get_retention_policy() -> RetentionPolicy {
+    RETENTION_POLICY.with(|p| p.borrow().get().clone())
+}
+
+#[update]
+fn set_canister_config(config: CanisterConfig) -> Result<CanisterConfig, String> {
+    if !is_admin(&msg_caller()) {
+        return Err("Only controllers can change the canister config".to_string());
+    }
+
+    CANISTER_CONFIG
+        .with(|c| c.borrow_mut().set(config.clone()))
+        .map_err(|e| format!("{:?}", e))?;
+
+    Ok(config)
+}
+
+#[query]
+fn get_canister_config() -> CanisterConfig {
+    CANISTER_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+async fn fetch_user(principal: Principal) -> Result<User, String> {
+    let canister = CANISTER_CONFIG
+        .with(|c| c.borrow().get().user_management_canister)
+        .ok_or("User management canister is not configured".to_string())?;
+
+    Call::bounded_wait(canister, "get_user")
+        .with_arg(principal)
+        .await
+        .map_err(|e| format!("Failed to look up user: {}", e))?
+        .candid::<Result<User, String>>()
+        .map_err(|e| format!("Failed to decode user: {}", e))?
+}
+
+// Suspended, deactivated and erased accounts cannot generate, schedule or share reports.
+async fn require_active(principal: Principal) -> Result<(), String> {
+    if fetch_user(principal).await?.status != AccountStatus::Active {
+        return Err("Account is not active".to_string());
+    }
+
+    Ok(())
 }
 
 fn generate_id() -> String {
     let timestamp = time();
-    let caller = ic_cdk::caller();
+    let caller = msg_caller();
     format!("{}-{}", timestamp, caller.to_text())
 }
 
 #[update]
-fn generate_report(
+async fn generate_report(
     title: String,
-    report_type: String,
+    report_type: ReportType,
     period_start: u64,
     period_end: u64,
     is_public: bool,
 ) -> Result<Report, String> {
-    let caller = ic_cdk::caller();
-    let current_time = time();
+    let caller = msg_caller();
+
+    if title.len() > MAX_TITLE_LENGTH {
+        return Err(format!(
+            "Title must be at most {} characters",
+            MAX_TITLE_LENGTH
+        ));
+    }
+
+    require_active(caller).await?;
+
+    create_report(
+        generate_id(),
+        caller,
+        title,
+        report_type,
+        period_start,
+        period_end,
+        is_public,
+    )
+}
 
-    let report_id = generate_id();
+fn create_report(
+    report_id: String,
+    owner: Principal,
+    title: String,
+    report_type: ReportType,
+    period_start: u64,
+    period_end: u64,
+    is_public: bool,
+) -> Result<Report, String> {
+    let current_time = time();
 
     // Generate report data based on type
-    let (data, summary) = match report_type.as_str() {
-        "SUPPLY_CHAIN_OVERVIEW" => generate_supply_chain_overview_data(),
-        "PERFORMANCE_REPORT" => generate_performance_report_data(caller),
-        "TRANSACTION_SUMMARY" => generate_transaction_summary_data(period_start, period_end),
-        "QUALITY_METRICS" => generate_quality_metrics_data(),
-        _ => (vec![], "Unknown report type".to_string()),
+    let (data, summary) = match report_type {
+        ReportType::SupplyChainOverview => generate_supply_chain_overview_data(),
+        ReportType::PerformanceReport => generate_performance_report_data(owner),
+        ReportType::TransactionSummary => {
+            generate_transaction_summary_data(period_start, period_end)
+        }
+        ReportType::QualityMetrics => generate_quality_metrics_data(period_start, period_end),
     };
 
     let report = Report {
         id: report_id.clone(),
         title,
         report_type,
-        generated_by: caller,
+        generated_by: owner,
         created_at: current_time,
         data,
         summary,
         period_start,
         period_end,
         is_public,
+        shared_with: vec![],
+        archived_at: None,
     };
 
-    REPORTS.with(|r| r.borrow_mut().insert(report_id.clone(), report.clone()));
+    store_report(&report)?;
+    enforce_user_retention(owner, current_time);
 
     Ok(report)
 }
 
-fn generate_supply_chain_overview_data() -> (Vec<(String, String)>, String) {
+#[update]
+async fn schedule_report(
+    report_type: ReportType,
+    cadence: Cadence,
+    is_public: bool,
+) -> Result<ReportSchedule, String> {
+    let caller = msg_caller();
+
+    require_active(caller).await?;
+    let current_time = time();
+
+    let schedule = ReportSchedule {
+        id: generate_id(),
+        owner: caller,
+        report_type,
+        next_run_at: current_time + cadence.interval(),
+        cadence,
+        is_public,
+        created_at: current_time,
+        last_run_at: None,
+        last_error: None,
+        run_history: vec![],
+    };
+
+    SCHEDULES.with(|s| s.borrow_mut().insert(schedule.id.clone(), schedule.clone()));
+
+    Ok(schedule)
+}
+
+#[update]
+fn cancel_schedule(schedule_id: String) -> Result<ReportSchedule, String> {
+    let caller = msg_caller();
+
+    let schedule = SCHEDULES.with(|s| {
+        s.borrow()
+            .get(&schedule_id)
+            .ok_or("Schedule not found".to_string())
+    })?;
+
+    if schedule.owner != caller {
+        return Err("Not authorized to cancel this schedule".to_string());
+    }
+
+    SCHEDULES.with(|s| s.borrow_mut().remove(&schedule_id));
+
+    Ok(schedule)
+}
+
+#[query]
+fn list_schedules() -> Vec<ReportSchedule> {
+    let caller = msg_caller();
+    SCHEDULES.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, schedule)| schedule.owner == caller)
+            .map(|(_, schedule)| schedule)
+            .collect()
+    })
+}
+
+async fn run_due_schedules() {
+    let current_time = time();
+
+    let due: Vec<ReportSchedule> = SCHEDULES.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, schedule)| schedule.next_run_at <= current_time)
+            .map(|(_, schedule)| schedule)
+            .collect()
+    });
+
+    for schedule in due {
+        let owner_check = require_active(schedule.owner).await;
+
+        // The schedule may have been deleted, or run by an overlapping tick, during the call.
+        let Some(mut schedule) = SCHEDULES.with(|s| s.borrow().get(&schedule.id)) else {
+            continue;
+        };
+        if schedule.next_run_at > current_time {
+            continue;
+        }
+
+        let period_end = current_time;
+        let period_start = period_end.saturating_sub(schedule.cadence.interval());
+
+        // Schedules of suspended, deactivated and erased owners keep advancing without
+        // producing reports, and record why.
+        let result = owner_check.and_then(|_| {
+            create_report(
+                format!("{}-run-{}", schedule.id, current_time),
+                schedule.owner,
+                format!("{:?} {:?}", schedule.cadence, schedule.report_type),
+                schedule.report_type.clone(),
+                period_start,
+                period_end,
+                schedule.is_public,
+            )
+        });
+
+        let run = match result {
+            Ok(report) => ScheduleRun {
+                ran_at: current_time,
+                report_id: Some(report.id),
+                error: None,
+            },
+            Err(e) => ScheduleRun {
+                ran_at: current_time,
+                report_id: None,
+                error: Some(e),
+            },
+        };
+
+        schedule.last_run_at = Some(current_time);
+        schedule.last_error = run.error.clone();
+        schedule.run_history.push(run);
+        if schedule.run_history.len() > MAX_SCHEDULE_RUN_HISTORY {
+            schedule.run_history.remove(0);
+        }
+
+        // Skip runs missed while the canister was stopped instead of replaying them.
+        while schedule.next_run_at <= current_time {
+            schedule.next_run_at += schedule.cadence.interval();
+        }
+
+        SCHEDULES.with(|s| s.borrow_mut().insert(schedule.id.clone(), schedule));
+    }
+}
+
+fn generate_supply_chain_overview_data() -> (Vec<(String, ReportValue)>, String) {
     // Mock data generation - in real implementation, this would query other canisters
     let data = vec![
-        ("total_products".to_string(), "1250".to_string()),
-        ("active_suppliers".to_string(), "45".to_string()),
-        ("active_transporters".to_string(), "23".to_string()),
-        ("active_warehouses".to_string(), "12".to_string()),
-        ("active_retailers".to_string(), "89".to_string()),
-        ("products_in_transit".to_string(), "156".to_string()),
-        ("completed_deliveries".to_string(), "3420".to_string()),
-        ("success_rate".to_string(), "94.5%".to_string()),
+        ("total_products".to_string(), ReportValue::Count(1250)),
+        ("active_suppliers".to_string(), ReportValue::Count(45)),
+        ("active_transporters".to_string(), ReportValue::Count(23)),
+        ("active_warehouses".to_string(), ReportValue::Count(12)),
+        ("active_retailers".to_string(), ReportValue::Count(89)),
+        ("products_in_transit".to_string(), ReportValue::Count(156)),
+        ("completed_deliveries".to_string(), ReportValue::Count(3420)),
+        ("success_rate".to_string(), ReportValue::Ratio(0.945)),
     ];
 
     let summary =
@@ -182,15 +976,27 @@ fn generate_supply_chain_overview_data() -> (Vec<(String, String)>, String) {
     (data, summary)
 }
 
-fn generate_performance_report_data(user_id: Principal) -> (Vec<(String, String)>, String) {
+fn generate_performance_report_data(user_id: Principal) -> (Vec<(String, ReportValue)>, String) {
     // Mock performance data
     let data = vec![
-        ("total_transactions".to_string(), "234".to_string()),
-        ("successful_transactions".to_string(), "221".to_string()),
-        ("failed_transactions".to_string(), "13".to_string()),
-        ("success_rate".to_string(), "94.4%".to_string()),
-        ("average_delivery_time".to_string(), "2.3 days".to_string()),
-        ("customer_satisfaction".to_string(), "4.7/5".to_string()),
+        ("total_transactions".to_string(), ReportValue::Count(234)),
+        (
+            "successful_transactions".to_string(),
+            ReportValue::Count(221),
+        ),
+        ("failed_transactions".to_string(), ReportValue::Count(13)),
+        (
+            "success_rate".to_string(),
+            ReportValue::Ratio(221.0 / 234.0),
+        ),
+        (
+            "average_delivery_time".to_string(),
+            ReportValue::Duration(55 * NANOS_PER_HOUR),
+        ),
+        (
+            "customer_satisfaction".to_string(),
+            ReportValue::Ratio(0.94),
+        ),
     ];
 
     let summary = format!(
@@ -204,17 +1010,33 @@ fn generate_performance_report_data(user_id: Principal) -> (Vec<(String, String)
 fn generate_transaction_summary_data(
     period_start: u64,
     period_end: u64,
-) -> (Vec<(String, String)>, String) {
+) -> (Vec<(String, ReportValue)>, String) {
+    let midpoint = period_start + period_end.saturating_sub(period_start) / 2;
     let data = vec![
-        ("period_start".to_string(), period_start.to_string()),
-        ("period_end".to_string(), period_end.to_string()),
-        ("total_transactions".to_string(), "1847".to_string()),
-        ("total_value".to_string(), "$2,456,789".to_string()),
+        ("total_transactions".to_string(), ReportValue::Count(1847)),
+        (
+            "total_value".to_string(),
+            ReportValue::Money {
+                amount: 245_678_900,
+                currency: "USD".to_string(),
+            },
+        ),
         (
             "average_transaction_value".to_string(),
-            "$1,330".to_string(),
+            ReportValue::Money {
+                amount: 133_000,
+                currency: "USD".to_string(),
+            },
+        ),
+        ("unique_users".to_string(), ReportValue::Count(167)),
+        (
+            "transactions_over_time".to_string(),
+            ReportValue::Series(vec![
+                (period_start, 912.0),
+                (midpoint, 935.0),
+                (period_end, 1847.0),
+            ]),
         ),
-        ("unique_users".to_string(), "167".to_string()),
     ];
 
     let summary =
@@ -223,22 +1045,125 @@ fn generate_transaction_summary_data(
     (data, summary)
 }
 
-fn generate_quality_metrics_data() -> (Vec<(String, String)>, String) {
-    let data = vec![
-        ("products_inspected".to_string(), "456".to_string()),
-        ("quality_passed".to_string(), "442".to_string()),
-        ("quality_failed".to_string(), "14".to_string()),
-        ("quality_rate".to_string(), "96.9%".to_string()),
-        ("average_quality_score".to_string(), "4.6/5".to_string()),
-        ("defect_rate".to_string(), "3.1%".to_string()),
+fn generate_quality_metrics_data(
+    period_start: u64,
+    period_end: u64,
+) -> (Vec<(String, ReportValue)>, String) {
+    let mut data = vec![
+        ("products_inspected".to_string(), ReportValue::Count(456)),
+        ("quality_passed".to_string(), ReportValue::Count(442)),
+        ("quality_failed".to_string(), ReportValue::Count(14)),
+        (
+            "quality_rate".to_string(),
+            ReportValue::Ratio(442.0 / 456.0),
+        ),
+        (
+            "average_quality_score".to_string(),
+            ReportValue::Ratio(0.92),
+        ),
+        ("defect_rate".to_string(), ReportValue::Ratio(14.0 / 456.0)),
+        (
+            "failures_by_category".to_string(),
+            ReportValue::Table {
+                columns: vec!["failed".to_string(), "share".to_string()],
+                rows: vec![
+                    (
+                        "packaging".to_string(),
+                        vec![ReportValue::Count(9), ReportValue::Ratio(9.0 / 14.0)],
+                    ),
+                    (
+                        "labelling".to_string(),
+                        vec![ReportValue::Count(5), ReportValue::Ratio(5.0 / 14.0)],
+                    ),
+                ],
+            },
+        ),
     ];
 
+    data.extend(dispute_metrics(period_start, period_end));
+
     let summary =
         "Quality metrics show 96.9% pass rate with an average quality score of 4.6/5.".to_string();
 
     (data, summary)
 }
 
+// Disputes closed within the period, broken down by outcome.
+fn dispute_metrics(period_start: u64, period_end: u64) -> Vec<(String, ReportValue)> {
+    let closures: Vec<DisputeClosure> = DISPUTE_CLOSURES.with(|d| {
+        d.borrow()
+            .iter()
+            .filter(|(_, closure)| {
+                closure.closed_at >= period_start && closure.closed_at <= period_end
+            })
+            .map(|(_, closure)| closure)
+            .collect()
+    });
+
+    let total = closures.len() as u64;
+    let share = |count: u64| {
+        if total > 0 {
+            count as f64 / total as f64
+        } else {
+            0.0
+        }
+    };
+
+    let outcomes = [
+        ("upheld", Some(DisputeOutcome::Upheld)),
+        ("partially_upheld", Some(DisputeOutcome::PartiallyUpheld)),
+        ("rejected", Some(DisputeOutcome::Rejected)),
+        ("withdrawn", None),
+    ];
+    let rows = outcomes
+        .into_iter()
+        .map(|(label, outcome)| {
+            let count = closures
+                .iter()
+                .filter(|closure| closure.outcome == outcome)
+                .count() as u64;
+            (
+                label.to_string(),
+                vec![ReportValue::Count(count), ReportValue::Ratio(share(count))],
+            )
+        })
+        .collect();
+
+    let total_duration: u64 = closures
+        .iter()
+        .map(|closure| closure.closed_at.saturating_sub(closure.opened_at))
+        .sum();
+
+    vec![
+        ("disputes_closed".to_string(), ReportValue::Count(total)),
+        (
+            "disputes_by_outcome".to_string(),
+            ReportValue::Table {
+                columns: vec!["disputes".to_string(), "share".to_string()],
+                rows,
+            },
+        ),
+        (
+            "average_dispute_duration".to_string(),
+            ReportValue::Duration(total_duration.checked_div(total).unwrap_or(0)),
+        ),
+    ]
+}
+
+// Called by supply_chain_backend when a dispute closes. Closures are keyed by dispute id, so a
+// report that is retried after a partial failure overwrites the earlier one.
+#[update]
+fn record_dispute_closure(closure: DisputeClosure) -> Result<(), String> {
+    let supply_chain_canister = CANISTER_CONFIG.with(|c| c.borrow().get().supply_chain_canister);
+    if supply_chain_canister != Some(msg_caller()) {
+        return Err("Only the supply chain canister can report disputes".to_string());
+    }
+
+    DISPUTE_CLOSURES.with(|d| d.borrow_mut().insert(closure.dispute_id.clone(), closure));
+
+    Ok(())
+}
+
 #[update]
 fn update_performance_metrics(
     user_id: Principal,
@@ -302,22 +1227,104 @@ fn generate_analytics() -> Result<SupplyChainAnalytics, String> {
     Ok(analytics)
 }
 
+// Canister controllers act as report admins.
+fn is_admin(principal: &Principal) -> bool {
+    ic_cdk::api::is_controller(principal)
+}
+
+fn can_view_report(report: &Report, caller: Principal) -> bool {
+    report.is_public
+        || report.generated_by == caller
+        || report.shared_with.contains(&caller)
+        || is_admin(&caller)
+}
+
+fn get_owned_report(report_id: &String, caller: Principal) -> Result<Report, String> {
+    let report = REPORTS.with(|r| {
+        r.borrow()
+            .get(report_id)
+            .ok_or("Report not found".to_string())
+    })?;
+
+    if report.generated_by != caller && !is_admin(&caller) {
+        return Err("Not authorized to share this report".to_string());
+    }
+
+    Ok(report)
+}
+
+#[update]
+async fn share_report(report_id: String, principal: Principal) -> Result<Report, String> {
+    let caller = msg_caller();
+
+    require_active(caller).await?;
+    let mut report = get_owned_report(&report_id, caller)?;
+
+    if !report.shared_with.contains(&principal) {
+        if report.shared_with.len() >= MAX_REPORT_SHARES {
+            return Err(format!(
+                "A report can be shared with at most {} principals",
+                MAX_REPORT_SHARES
+            ));
+        }
+        report.shared_with.push(principal);
+        save_report_record(&report)?;
+    }
+
+    Ok(with_body(report))
+}
+
+#[update]
+fn revoke_report_share(report_id: String, principal: Principal) -> Result<Report, String> {
+    let mut report = get_owned_report(&report_id, msg_caller())?;
+
+    if !report.shared_with.contains(&principal) {
+        return Err("Report is not shared with this principal".to_string());
+    }
+
+    report.shared_with.retain(|p| *p != principal);
+    save_report_record(&report)?;
+
+    Ok(with_body(report))
+}
+
 #[query]
 fn get_report(report_id: String) -> Result<Report, String> {
-    REPORTS.with(|r| {
+    let report = REPORTS.with(|r| {
         r.borrow()
             .get(&report_id)
             .ok_or("Report not found".to_string())
-    })
+    })?;
+
+    // Private reports are reported as missing so their IDs can't be probed.
+    if !can_view_report(&report, msg_caller()) {
+        return Err("Report not found".to_string());
+    }
+
+    Ok(with_body(report))
 }
 
 #[query]
 fn get_reports_by_user(user_id: Principal) -> Vec<Report> {
+    let caller = msg_caller();
     REPORTS.with(|r| {
         r.borrow()
             .iter()
             .filter(|(_, report)| report.generated_by == user_id)
-            .map(|(_, report)| report)
+            .filter(|(_, report)| can_view_report(report, caller))
+            .map(|(_, report)| with_body(report))
+            .collect()
+    })
+}
+
+#[query]
+fn get_shared_reports() -> Vec<Report> {
+    let caller = msg_caller();
+    REPORTS.with(|r| {
+        r.borrow()
+            .iter()
+            .filter(|(_, report)| report.shared_with.contains(&caller))
+            .map(|(_, report)| with_body(report))
             .collect()
     })
 }
@@ -328,18 +1335,20 @@ fn get_public_reports() -> Vec<Report> {
         r.borrow()
             .iter()
             .filter(|(_, report)| report.is_public)
-            .map(|(_, report)| report)
+            .map(|(_, report)| with_body(report))
             .collect()
     })
 }
 
 #[query]
-fn get_reports_by_type(report_type: String) -> Vec<Report> {
+fn get_reports_by_type(report_type: ReportType) -> Vec<Report> {
+    let caller = msg_caller();
     REPORTS.with(|r| {
         r.borrow()
             .iter()
             .filter(|(_, report)| report.report_type == report_type)
-            .map(|(_, report)| report)
+            .filter(|(_, report)| can_view_report(report, caller))
+            .map(|(_, report)| with_body(report))
             .collect()
     })
 }
@@ -381,4 +1390,321 @@ fn get_top_performers(limit: u32) -> Vec<PerformanceMetrics> {
     metrics
 }
 
+fn csv_field(value: &str) -> String {
+    if value.contains(',') || value.contains('"') || value.contains('\n') || value.contains('\r') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// Splits a value into (kind, value, unit) CSV columns. Series and tables don't fit a single
+// cell, so they are embedded as JSON.
+fn csv_value(value: &ReportValue) -> (&'static str, String, String) {
+    match value {
+        ReportValue::Count(count) => ("count", count.to_string(), String::new()),
+        ReportValue::Ratio(ratio) => ("ratio", ratio.to_string(), String::new()),
+        ReportValue::Money { amount, currency } => ("money", amount.to_string(), currency.clone()),
+        ReportValue::Duration(nanos) => ("duration", nanos.to_string(), "ns".to_string()),
+        ReportValue::Series(_) => (
+            "series",
+            serde_json::to_string(value).unwrap_or_default(),
+            String::new(),
+        ),
+        ReportValue::Table { .. } => (
+            "table",
+            serde_json::to_string(value).unwrap_or_default(),
+            String::new(),
+        ),
+    }
+}
+
+fn render_report(report: &Report, format: &str) -> Result<(Vec<u8>, &'static str), String> {
+    match format {
+        "csv" => {
+            let mut csv = String::from("key,kind,value,unit\r\n");
+            for (key, value) in &report.data {
+                let (kind, value, unit) = csv_value(value);
+                csv.push_str(&format!(
+                    "{},{},{},{}\r\n",
+                    csv_field(key),
+                    kind,
+                    csv_field(&value),
+                    csv_field(&unit)
+                ));
+            }
+            Ok((csv.into_bytes(), "text/csv; charset=utf-8"))
+        }
+        "json" => serde_json::to_vec(report)
+            .map(|body| (body, "application/json"))
+            .map_err(|e| e.to_string()),
+        _ => Err(format!("Unsupported export format: {}", format)),
+    }
+}
+
+// Loads a report and renders it for download. The HTTP gateway calls anonymously, so only
+// public reports are served over HTTP; private and shared reports are fetched through the
+// authenticated candid queries instead.
+fn export_report(report_id: &str, format: &str) -> Result<(Vec<u8>, &'static str), (u16, String)> {
+    let report = REPORTS
+        .with(|r| r.borrow().get(&report_id.to_string()))
+        .ok_or((404, "Report not found".to_string()))?;
+
+    if !report.is_public {
+        return Err((404, "Report not found".to_string()));
+    }
+
+    render_report(&with_body(report), format).map_err(|e| (400, e))
+}
+
+fn text_response(status_code: u16, message: String) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![(
+            "Content-Type".to_string(),
+            "text/plain; charset=utf-8".to_string(),
+        )],
+        body: message.into_bytes(),
+        streaming_strategy: None,
+    }
+}
+
+fn chunk(body: &[u8], index: usize) -> Vec<u8> {
+    body.chunks(HTTP_CHUNK_SIZE)
+        .nth(index)
+        .map(|c| c.to_vec())
+        .unwrap_or_default()
+}
+
+fn next_token(body: &[u8], token: StreamingCallbackToken) -> Option<StreamingCallbackToken> {
+    let next = token.index + 1;
+    if (next as usize) * HTTP_CHUNK_SIZE < body.len() {
+        Some(StreamingCallbackToken {
+            index: next,
+            ..token
+        })
+    } else {
+        None
+    }
+}
+
+// Report ids come from the request path, so anything outside a conservative character set is
+// replaced before the id is put into a quoted header value.
+fn attachment_filename(report_id: &str, format: &str) -> String {
+    format!("{}.{}", report_id, format)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    if request.method != "GET" {
+        return text_response(405, "Method not allowed".to_string());
+    }
+
+    let path = request.url.split('?').next().unwrap_or_default();
+    let Some((report_id, format)) = path
+        .strip_prefix("/reports/")
+        .and_then(|file| file.rsplit_once('.'))
+    else {
+        return text_response(404, "Not found".to_string());
+    };
+
+    let (body, content_type) = match export_report(report_id, format) {
+        Ok(rendered) => rendered,
+        Err((status_code, message)) => return text_response(status_code, message),
+    };
+
+    let token = StreamingCallbackToken {
+        report_id: report_id.to_string(),
+        format: format.to_string(),
+        index: 0,
+    };
+    let streaming_strategy = next_token(&body, token).map(|token| StreamingStrategy::Callback {
+        callback: StreamingCallbackFunction::new(
+            canister_self(),
+            "http_request_streaming_callback".to_string(),
+        ),
+        token,
+    });
+
+    HttpResponse {
+        status_code: 200,
+        headers: vec![
+            ("Content-Type".to_string(), content_type.to_string()),
+            (
+                "Content-Disposition".to_string(),
+                format!(
+                    "attachment; filename=\"{}\"",
+                    attachment_filename(report_id, format)
+                ),
+            ),
+        ],
+        body: chunk(&body, 0),
+        streaming_strategy,
+    }
+}
+
+#[query]
+fn http_request_streaming_callback(token: StreamingCallbackToken) -> StreamingCallbackHttpResponse {
+    // The token comes back from the client, so access is checked again for every chunk.
+    match export_report(&token.report_id, &token.format) {
+        Ok((body, _)) => StreamingCallbackHttpResponse {
+            body: chunk(&body, token.index as usize),
+            token: next_token(&body, token),
+        },
+        Err((_, message)) => ic_cdk::trap(message),
+    }
+}
+
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attachment_filename_keeps_safe_ids() {
+        assert_eq!(
+            attachment_filename("1700000000-aaaaa-aa", "csv"),
+            "1700000000-aaaaa-aa.csv"
+        );
+    }
+
+    #[test]
+    fn attachment_filename_replaces_header_metacharacters() {
+        assert_eq!(
+            attachment_filename("a\"; x=\"b\r\nSet-Cookie: c", "json"),
+            "a___x__b__Set-Cookie__c.json"
+        );
+    }
+
+    fn report(id: &str, owner: Principal, created_at: u64) -> Report {
+        Report {
+            id: id.to_string(),
+            title: "Overview".to_string(),
+            report_type: ReportType::SupplyChainOverview,
+            generated_by: owner,
+            created_at,
+            data: vec![("products".to_string(), ReportValue::Count(3))],
+            summary: String::new(),
+            period_start: 0,
+            period_end: created_at,
+            is_public: false,
+            shared_with: vec![],
+            archived_at: None,
+        }
+    }
+
+    fn set_policy(max_report_age: Option<u64>, max_reports_per_user: u32, action: RetentionAction) {
+        RETENTION_POLICY
+            .with(|p| {
+                p.borrow_mut().set(RetentionPolicy {
+                    max_report_age,
+                    max_reports_per_user,
+                    action,
+                })
+            })
+            .unwrap();
+    }
+
+    fn stored(report_id: &str) -> Option<Report> {
+        REPORTS.with(|r| r.borrow().get(&report_id.to_string()))
+    }
+
+    #[test]
+    fn user_retention_archives_only_the_owners_oldest_reports() {
+        let owner = Principal::from_slice(&[1]);
+        let other = Principal::from_slice(&[2]);
+        set_policy(None, 2, RetentionAction::Archive);
+        for (id, created_at) in [("r1", 10), ("r2", 30), ("r3", 20)] {
+            store_report(&report(id, owner, created_at)).unwrap();
+        }
+        store_report(&report("o1", other, 5)).unwrap();
+
+        enforce_user_retention(owner, 100);
+
+        let archived = stored("r1").unwrap();
+        assert_eq!(archived.archived_at, Some(100));
+        assert!(with_body(archived).data.is_empty());
+        assert!(stored("r2").unwrap().archived_at.is_none());
+        assert!(stored("r3").unwrap().archived_at.is_none());
+        assert!(stored("o1").unwrap().archived_at.is_none());
+        assert_eq!(OWNER_REPORTS.with(|o| o.borrow().len()), 3);
+    }
+
+    #[test]
+    fn user_retention_deletes_when_configured() {
+        let owner = Principal::from_slice(&[1]);
+        set_policy(None, 1, RetentionAction::Delete);
+        store_report(&report("r1", owner, 10)).unwrap();
+        store_report(&report("r2", owner, 20)).unwrap();
+
+        enforce_user_retention(owner, 100);
+
+        assert!(stored("r1").is_none());
+        assert!(REPORT_CHUNKS.with(|c| c.borrow().get(&chunk_key("r1", 0)).is_none()));
+        assert!(stored("r2").is_some());
+    }
+
+    #[test]
+    fn archive_clears_inline_legacy_data() {
+        let owner = Principal::from_slice(&[1]);
+        let legacy = report("legacy", owner, 10);
+        REPORTS.with(|r| r.borrow_mut().insert(legacy.id.clone(), legacy.clone()));
+
+        apply_retention_action(legacy, &RetentionAction::Archive, 100);
+
+        assert!(stored("legacy").unwrap().data.is_empty());
+    }
+
+    #[test]
+    fn purge_batch_only_touches_expired_live_reports() {
+        let owner = Principal::from_slice(&[1]);
+        set_policy(Some(50), 200, RetentionAction::Delete);
+        store_report(&report("old", owner, 10)).unwrap();
+        store_report(&report("new", owner, 90)).unwrap();
+
+        assert_eq!(purge_expired_batch(50, None, 100), None);
+
+        assert!(stored("old").is_none());
+        assert!(stored("new").is_some());
+        assert_eq!(OWNER_REPORTS.with(|o| o.borrow().len()), 1);
+    }
+
+    #[test]
+    fn purge_batch_resumes_after_a_full_batch() {
+        let owner = Principal::from_slice(&[1]);
+        set_policy(Some(50), u32::MAX, RetentionAction::Archive);
+        for index in 0..=RETENTION_PURGE_BATCH_SIZE {
+            store_report(&report(&format!("r{:04}", index), owner, 10)).unwrap();
+        }
+
+        let last = purge_expired_batch(50, None, 100);
+        assert_eq!(
+            last,
+            Some(format!("r{:04}", RETENTION_PURGE_BATCH_SIZE - 1))
+        );
+        let final_id = format!("r{:04}", RETENTION_PURGE_BATCH_SIZE);
+        assert!(stored(&final_id).unwrap().archived_at.is_none());
+
+        assert_eq!(purge_expired_batch(50, last, 100), None);
+        assert_eq!(stored(&final_id).unwrap().archived_at, Some(100));
+    }
+
+    #[test]
+    fn oversized_record_is_rejected_on_save() {
+        let mut shared = report("r1", Principal::from_slice(&[1]), 10);
+        shared.summary = "x".repeat(MAX_REPORT_RECORD_SIZE as usize);
+
+        assert!(save_report_record(&shared).is_err());
+        assert!(stored("r1").is_none());
+    }
+}
@@ -1,15 +1,45 @@
 use candid::{CandidType, Deserialize, Principal};
-use ic_cdk::api::time;
+use ic_cdk::api::{is_controller, msg_caller, time};
+use ic_cdk::call::Call;
 use ic_cdk_macros::*;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
 use serde::Serialize;
 use std::borrow::Cow;
 use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+use std::time::Duration;
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
-#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Owners are warned once a certification is within this window of lapsing.
+const CERTIFICATION_WARNING_WINDOW: u64 = 30 * NANOS_PER_DAY;
+const CERTIFICATION_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+const MAX_SEARCH_PAGE_SIZE: u32 = 100;
+// Upper bound on products read by one search call, so broad queries stay within the
+// instruction limit. Callers continue from the returned cursor.
+const MAX_SEARCH_SCAN: usize = 2000;
+const MAX_CERTIFICATIONS: usize = 20;
+const MAX_CERTIFICATION_FIELD_LENGTH: usize = 100;
+const MAX_SHIPMENT_PRODUCTS: usize = 100;
+const MAX_SHIPMENT_LEGS: usize = 10;
+const MAX_LOCATION_LENGTH: usize = 200;
+const MAX_EVIDENCE_HASH_LENGTH: usize = 128;
+const MAX_PURCHASE_ORDER_LINES: usize = 50;
+const MAX_PURCHASE_ORDER_LINE_TEXT_LENGTH: usize = 200;
+const MAX_PURCHASE_ORDER_NOTES_LENGTH: usize = 1000;
+const MAX_DISPUTE_CLAIMS: usize = 10;
+const MAX_DISPUTE_EVIDENCE: usize = 20;
+const MAX_DISPUTE_MESSAGES: usize = 100;
+const MAX_DISPUTE_TEXT_LENGTH: usize = 1000;
+// Closed disputes that could not be reported to rating_backend and reporting_backend when
+// they closed are retried this often.
+const DISPUTE_REPORT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
 pub enum UserRole {
     Supplier,
     Transporter,
@@ -29,6 +59,16 @@ pub enum ProductStatus {
     Damaged,
 }
 
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Certification {
+    pub scheme: String, // e.g. "ISO9001", "Organic"
+    pub issuer: String,
+    pub certificate_number: String,
+    pub valid_from: u64,
+    pub valid_until: u64,
+    pub document_hash: Option<String>,
+}
+
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
 pub struct Product {
     pub id: String,
@@ -45,7 +85,9 @@ pub struct Product {
     pub quantity: u32,
     pub category: String,
     pub origin: String,
-    pub certifications: Vec<String>,
+    #[serde(default)]
+    pub certifications: Vec<Certification>,
+    pub owner_organization: Option<String>, // members of this organization can act on the product
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
@@ -53,6 +95,7 @@ pub struct TrackingEvent {
     pub id: String,
     pub product_id: String,
     pub user_id: Principal,
+    pub acting_for: Option<DelegationSubject>, // set when user_id acted under a delegation
     pub user_role: UserRole,
     pub event_type: String,
     pub description: String,
@@ -67,15 +110,378 @@ pub struct Transfer {
     pub product_id: String,
     pub from_user: Principal,
     pub to_user: Principal,
+    pub initiated_by: Principal, // from_user, or the delegate or member acting for them
     pub transfer_type: String,
     pub status: String,
     pub initiated_at: u64,
     pub completed_at: Option<u64>,
     pub notes: String,
+    #[serde(default = "unrecorded_quantity")] // backfilled from the product by post_upgrade
+    pub quantity: u32,
+    pub purchase_order_line: Option<PurchaseOrderLineRef>,
+    pub proof_of_delivery: Option<ProofOfDelivery>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum DeliveryCondition {
+    Ok,
+    Damaged,
+    Short,
+}
+
+// The receiver's attestation of what arrived. Only the hash of any photo or document is kept;
+// the file itself stays off-chain.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ProofOfDelivery {
+    pub quantity_received: u32,
+    pub condition: DeliveryCondition,
+    pub evidence_hash: Option<String>,
+    pub location: String,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum DisputeSubject {
+    Transfer(String),
+    Shipment(String),
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum DisputeStatus {
+    Open,
+    Resolved,
+    Withdrawn,
+}
+
+// Whether the arbitrator found for the claimant.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum DisputeOutcome {
+    Upheld,
+    PartiallyUpheld,
+    Rejected,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct DisputeClaim {
+    pub submitted_by: Principal,
+    pub description: String,
+    pub created_at: u64,
+}
+
+// Only the hash of a photo or document is kept, as with proof of delivery.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct DisputeEvidence {
+    pub submitted_by: Principal,
+    pub hash: String,
+    pub description: String,
+    pub submitted_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct DisputeMessage {
+    pub author: Principal,
+    pub body: String,
+    pub sent_at: u64,
+}
+
+// An arbitrator's ruling. The product status and new owner, if set, are applied to every
+// product in the dispute.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DisputeRulingInput {
+    pub outcome: DisputeOutcome,
+    pub notes: String,
+    pub product_status: Option<ProductStatus>,
+    pub new_owner: Option<Principal>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct DisputeRuling {
+    pub arbitrator: Principal,
+    pub outcome: DisputeOutcome,
+    pub notes: String,
+    pub product_status: Option<ProductStatus>,
+    pub new_owner: Option<Principal>,
+    pub ruled_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Dispute {
+    pub id: String,
+    pub subject: DisputeSubject,
+    pub claimant: Principal,
+    pub respondent: Principal,
+    pub product_ids: Vec<String>,
+    pub claims: Vec<DisputeClaim>,
+    pub evidence: Vec<DisputeEvidence>,
+    pub messages: Vec<DisputeMessage>,
+    pub status: DisputeStatus,
+    pub ruling: Option<DisputeRuling>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub closed_at: Option<u64>,
+    pub reported_at: Option<u64>, // when the closure reached rating_backend and reporting_backend
+}
+
+// What rating_backend and reporting_backend are told about a closed dispute. The outcome is
+// None for withdrawn disputes.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DisputeClosure {
+    pub dispute_id: String,
+    pub claimant: Principal,
+    pub respondent: Principal,
+    pub outcome: Option<DisputeOutcome>,
+    pub opened_at: u64,
+    pub closed_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct PurchaseOrderLineRef {
+    pub purchase_order_id: String,
+    pub line_number: u32,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum PurchaseOrderStatus {
+    Submitted,
+    Accepted,
+    Rejected,
+    Cancelled,
+    PartiallyFulfilled,
+    Fulfilled,
+    Received,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PurchaseOrderLineInput {
+    pub description: String,
+    pub category: String,
+    pub quantity: u32,
+    pub unit_price: f64,
+}
+
+// Shipped and received quantities are filled in by the transfers that reference the line.
+// Backordered is the part of the outstanding quantity the supplier has said will ship later.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct PurchaseOrderLine {
+    pub line_number: u32,
+    pub description: String,
+    pub category: String,
+    pub quantity_ordered: u32,
+    pub unit_price: f64,
+    pub quantity_shipped: u32,
+    pub quantity_received: u32,
+    pub quantity_backordered: u32,
+    pub backorder_expected_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct PurchaseOrder {
+    pub id: String,
+    pub buyer: Principal,
+    pub supplier: Principal,
+    pub lines: Vec<PurchaseOrderLine>,
+    pub status: PurchaseOrderStatus,
+    pub notes: String,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum LegStatus {
+    Planned,
+    PickedUp,
+    Delivered,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ShipmentLegInput {
+    pub origin: String,
+    pub destination: String,
+    pub carrier: Principal,
+    pub eta: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ShipmentLeg {
+    pub origin: String,
+    pub destination: String,
+    pub carrier: Principal,
+    pub eta: u64,
+    pub status: LegStatus,
+    pub picked_up_at: Option<u64>,
+    pub delivered_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum ShipmentStatus {
+    Planned,
+    InTransit,
+    Delivered,
+    Cancelled,
+}
+
+// A group of products moved from the shipper to the consignee over one or more legs, each
+// handled by its own carrier. Legs run in order; each one is picked up by its carrier and
+// its delivery confirmed by whoever receives it next.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Shipment {
+    pub id: String,
+    pub shipper: Principal,
+    pub consignee: Principal,
+    pub product_ids: Vec<String>,
+    pub legs: Vec<ShipmentLeg>,
+    pub status: ShipmentStatus,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct ProductSearchQuery {
+    pub text: Option<String>, // every word must prefix a word of the name, description, category or origin
+    pub status: Option<ProductStatus>,
+    pub category: Option<String>,
+    pub origin: Option<String>,
+    pub certification: Option<String>, // certification scheme
+    pub min_price: Option<f64>,
+    pub max_price: Option<f64>,
+    pub created_after: Option<u64>,
+    pub created_before: Option<u64>,
+    pub expires_after: Option<u64>,
+    pub expires_before: Option<u64>,
+    pub cursor: Option<String>, // next_cursor of the previous page
+    pub limit: u32,
+}
+
+// Number of matching products for each value of a field, among the products one search call
+// read.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct ProductFacets {
+    pub status: Vec<(String, u64)>,
+    pub category: Vec<(String, u64)>,
+    pub origin: Vec<(String, u64)>,
+    pub certification: Vec<(String, u64)>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ProductSearchResult {
+    pub products: Vec<Product>,
+    pub total: u64,
+    pub facets: ProductFacets,
+    pub next_cursor: Option<String>,
+}
+
+// Principals of the other canisters this one calls, set by a controller after deployment.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct CanisterConfig {
+    pub user_management_canister: Option<Principal>,
+    pub rating_canister: Option<Principal>,
+    pub reporting_canister: Option<Principal>,
+}
+
+// Mirrors of user_management_backend's account and organization types.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+enum OrgRole {
+    Owner,
+    Operator,
+    Viewer,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+enum AccountStatus {
+    Active,
+    Suspended,
+    Deactivated,
+    Erased,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct User {
+    role: UserRole,
+    status: AccountStatus,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Membership {
+    role: OrgRole,
+}
+
+// Mirrors of user_management_backend's delegation types.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum DelegationScope {
+    CreateProduct,
+    TransferProduct,
+    UpdateProductStatus,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum DelegationSubject {
+    User(Principal),
+    Organization(String),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Delegation {
+    delegate: Principal,
+    subject: DelegationSubject,
+    scopes: Vec<DelegationScope>,
+    expires_at: u64,
+    granted_by: Principal,
+    revoked_at: Option<u64>,
+}
+
+// Raised for the current owner of a product when one of its certifications is about to lapse.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct CertificationAlert {
+    pub id: String,
+    pub owner: Principal,
+    pub product_id: String,
+    pub scheme: String,
+    pub certificate_number: String,
+    pub valid_until: u64,
+    pub created_at: u64,
+}
+
+// Products stored before certifications were structured list them by scheme name only. They
+// are kept as certifications of that scheme that never lapse, since no expiry was recorded.
+fn migrate_legacy_product(bytes: &[u8]) -> Product {
+    let mut product: serde_json::Value = serde_json::from_slice(bytes).unwrap();
+
+    if let Some(certifications) = product["certifications"].as_array() {
+        let certifications: Vec<serde_json::Value> = certifications
+            .iter()
+            .map(|certification| match certification.as_str() {
+                Some(scheme) => serde_json::json!(Certification {
+                    scheme: scheme.to_string(),
+                    issuer: String::new(),
+                    certificate_number: String::new(),
+                    valid_from: 0,
+                    valid_until: u64::MAX,
+                    document_hash: None,
+                }),
+                None => certification.clone(),
+            })
+            .collect();
+        product["certifications"] = serde_json::Value::Array(certifications);
+    }
+
+    serde_json::from_value(product).unwrap()
 }
 
 impl Storable for Product {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap_or_else(|_| migrate_legacy_product(&bytes))
+    }
+
+    // Names, descriptions and the other free-text fields are not length limited.
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Storable for CertificationAlert {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(serde_json::to_vec(self).unwrap())
     }
 
@@ -85,13 +491,58 @@ impl Storable for Product {
 
     const BOUND: ic_stable_structures::storable::Bound =
         ic_stable_structures::storable::Bound::Bounded {
-            max_size: 2048,
+            max_size: 1024,
             is_fixed_size: false,
         };
 }
 
-impl Storable for TrackingEvent {
-    fn to_bytes(&self) -> Cow<[u8]> {
+impl Storable for Dispute {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    // Claims, messages and evidence descriptions are capped in characters, but JSON escaping
+    // can grow each character to several bytes.
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Storable for PurchaseOrder {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    // Up to MAX_PURCHASE_ORDER_LINES lines of text can exceed any small fixed bound once
+    // JSON-escaped.
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Storable for Shipment {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    // MAX_SHIPMENT_PRODUCTS ids and MAX_SHIPMENT_LEGS legs with locations of up to
+    // MAX_LOCATION_LENGTH characters exceed any small fixed bound once JSON-escaped.
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Storable for CanisterConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(serde_json::to_vec(self).unwrap())
     }
 
@@ -101,13 +552,13 @@ impl Storable for TrackingEvent {
 
     const BOUND: ic_stable_structures::storable::Bound =
         ic_stable_structures::storable::Bound::Bounded {
-            max_size: 1024,
+            max_size: 512,
             is_fixed_size: false,
         };
 }
 
-impl Storable for Transfer {
-    fn to_bytes(&self) -> Cow<[u8]> {
+impl Storable for TrackingEvent {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(serde_json::to_vec(self).unwrap())
     }
 
@@ -117,11 +568,42 @@ impl Storable for Transfer {
 
     const BOUND: ic_stable_structures::storable::Bound =
         ic_stable_structures::storable::Bound::Bounded {
-            max_size: 1024,
+            max_size: 2048,
             is_fixed_size: false,
         };
 }
 
+// Transfers stored before quantities were recorded moved the whole product. They are given
+// this placeholder on decode until post_upgrade fills in the product's quantity.
+fn unrecorded_quantity() -> u32 {
+    u32::MAX
+}
+
+// Transfers stored before delegation existed were always initiated by their sender.
+fn migrate_legacy_transfer(bytes: &[u8]) -> Transfer {
+    let mut transfer: serde_json::Value = serde_json::from_slice(bytes).unwrap();
+
+    if transfer.get("initiated_by").is_none() {
+        transfer["initiated_by"] = transfer["from_user"].clone();
+    }
+
+    serde_json::from_value(transfer).unwrap()
+}
+
+impl Storable for Transfer {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap_or_else(|_| migrate_legacy_transfer(&bytes))
+    }
+
+    // Notes are free text, and the proof of delivery adds a location and evidence hash.
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
         MemoryManager::init(DefaultMemoryImpl::default())
@@ -144,27 +626,535 @@ thread_local! {
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
         )
     );
+
+    // Keyed by certification_alert_id, so each certificate is warned about once per owner and
+    // expiry.
+    static CERTIFICATION_ALERTS: RefCell<StableBTreeMap<String, CertificationAlert, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+        )
+    );
+
+    static CANISTER_CONFIG: RefCell<StableCell<CanisterConfig, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
+            CanisterConfig::default(),
+        )
+        .expect("Failed to initialize canister config")
+    );
+
+    // Inverted index over products. Keys are "term:{word}|{product_id}" for the words of the
+    // searchable text fields, plus "status:", "category:", "origin:" and "cert:" facet keys.
+    static PRODUCT_INDEX: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+        )
+    );
+
+    static SHIPMENTS: RefCell<StableBTreeMap<String, Shipment, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+        )
+    );
+
+    static PURCHASE_ORDERS: RefCell<StableBTreeMap<String, PurchaseOrder, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+        )
+    );
+
+    static DISPUTES: RefCell<StableBTreeMap<String, Dispute, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+        )
+    );
+}
+
+#[init]
+fn init() {
+    start_timers();
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    // Products created before the search index existed are indexed once.
+    if PRODUCT_INDEX.with(|i| i.borrow().is_empty()) {
+        let products: Vec<Product> =
+            PRODUCTS.with(|p| p.borrow().iter().map(|(_, product)| product).collect());
+        for product in products {
+            update_product_index(None, &product);
+        }
+    }
+    backfill_transfer_quantities();
+    rekey_certification_alerts();
+
+    start_timers();
+}
+
+fn backfill_transfer_quantities() {
+    let transfers: Vec<Transfer> = TRANSFERS.with(|t| {
+        t.borrow()
+            .iter()
+            .map(|(_, transfer)| transfer)
+            .filter(|transfer| transfer.quantity == unrecorded_quantity())
+            .collect()
+    });
+
+    for mut transfer in transfers {
+        transfer.quantity = load_product(&transfer.product_id).map_or(0, |p| p.quantity);
+        TRANSFERS.with(|t| t.borrow_mut().insert(transfer.id.clone(), transfer));
+    }
+}
+
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(CERTIFICATION_CHECK_INTERVAL, warn_expiring_certifications);
+    ic_cdk_timers::set_timer_interval(DISPUTE_REPORT_INTERVAL, || {
+        ic_cdk::futures::spawn(report_closed_disputes())
+    });
+}
+
+// Lowercased alphanumeric words of `text`, sorted and deduplicated.
+fn search_tokens(text: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(|token| token.to_lowercase())
+        .collect();
+    tokens.sort();
+    tokens.dedup();
+    tokens
+}
+
+fn facet_value(value: &str) -> String {
+    value.trim().to_lowercase()
+}
+
+fn index_keys(product: &Product) -> Vec<String> {
+    let text = format!(
+        "{} {} {} {}",
+        product.name, product.description, product.category, product.origin
+    );
+    let mut keys: Vec<String> = search_tokens(&text)
+        .into_iter()
+        .map(|token| format!("term:{}|{}", token, product.id))
+        .collect();
+
+    keys.push(format!("status:{:?}|{}", product.status, product.id));
+    keys.push(format!(
+        "category:{}|{}",
+        facet_value(&product.category),
+        product.id
+    ));
+    keys.push(format!(
+        "origin:{}|{}",
+        facet_value(&product.origin),
+        product.id
+    ));
+    for certification in &product.certifications {
+        keys.push(format!(
+            "cert:{}|{}",
+            facet_value(&certification.scheme),
+            product.id
+        ));
+    }
+
+    keys
+}
+
+fn update_product_index(previous: Option<&Product>, product: &Product) {
+    PRODUCT_INDEX.with(|i| {
+        let mut index = i.borrow_mut();
+        for key in previous.map(index_keys).unwrap_or_default() {
+            index.remove(&key);
+        }
+        for key in index_keys(product) {
+            index.insert(key, product.id.clone());
+        }
+    });
+}
+
+// Writes a product, keeping the search index in step.
+fn save_product(product: &Product) {
+    let previous = PRODUCTS.with(|p| p.borrow_mut().insert(product.id.clone(), product.clone()));
+    update_product_index(previous.as_ref(), product);
+}
+
+// Ids of products with an index key starting with `prefix`.
+fn index_lookup(prefix: &str) -> BTreeSet<String> {
+    PRODUCT_INDEX.with(|i| {
+        i.borrow()
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(_, product_id)| product_id)
+            .collect()
+    })
 }
 
 fn generate_id() -> String {
     let timestamp = time();
-    let caller = ic_cdk::caller();
+    let caller = msg_caller();
     format!("{}-{}", timestamp, caller.to_text())
 }
 
 #[update]
-fn create_product(
-    name: String,
-    description: String,
-    batch_number: String,
-    expiry_date: Option<u64>,
-    price: f64,
-    quantity: u32,
-    category: String,
-    origin: String,
-    certifications: Vec<String>,
+fn set_canister_config(config: CanisterConfig) -> Result<CanisterConfig, String> {
+    if !is_controller(&msg_caller()) {
+        return Err("Only controllers can change the canister config".to_string());
+    }
+
+    CANISTER_CONFIG
+        .with(|c| c.borrow_mut().set(config.clone()))
+        .map_err(|e| format!("{:?}", e))?;
+
+    Ok(config)
+}
+
+#[query]
+fn get_canister_config() -> CanisterConfig {
+    CANISTER_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+async fn fetch_user(principal: Principal) -> Result<User, String> {
+    let canister = CANISTER_CONFIG
+        .with(|c| c.borrow().get().user_management_canister)
+        .ok_or("User management canister is not configured".to_string())?;
+
+    Call::bounded_wait(canister, "get_user")
+        .with_arg(principal)
+        .await
+        .map_err(|e| format!("Failed to look up user: {}", e))?
+        .candid::<Result<User, String>>()
+        .map_err(|e| format!("Failed to decode user: {}", e))?
+}
+
+// Suspended, deactivated and erased accounts cannot act on products, whether directly or
+// through a delegate. Returns the caller's account.
+async fn require_active(
+    caller: Principal,
+    delegation: Option<&Delegation>,
+) -> Result<User, String> {
+    let account = fetch_user(caller).await?;
+    if account.status != AccountStatus::Active {
+        return Err(format!("Account {} is not active", caller.to_text()));
+    }
+
+    let principal = acting_principal(caller, delegation);
+    if principal != caller && fetch_user(principal).await?.status != AccountStatus::Active {
+        return Err(format!("Account {} is not active", principal.to_text()));
+    }
+
+    Ok(account)
+}
+
+async fn fetch_membership(
+    organization_id: &str,
+    member: Principal,
+) -> Result<Option<Membership>, String> {
+    let canister = CANISTER_CONFIG
+        .with(|c| c.borrow().get().user_management_canister)
+        .ok_or("User management canister is not configured".to_string())?;
+
+    Call::bounded_wait(canister, "get_membership")
+        .with_args(&(organization_id.to_string(), member))
+        .await
+        .map_err(|e| format!("Failed to look up membership: {}", e))?
+        .candid::<Option<Membership>>()
+        .map_err(|e| format!("Failed to decode membership: {}", e))
+}
+
+// Owners and operators of an organization can act on its products; viewers cannot.
+async fn require_org_operator(organization_id: &str, principal: Principal) -> Result<(), String> {
+    match fetch_membership(organization_id, principal).await? {
+        Some(membership) if membership.role != OrgRole::Viewer => Ok(()),
+        _ => Err("Not an operator of this organization".to_string()),
+    }
+}
+
+async fn fetch_delegation(delegation_id: &str) -> Result<Option<Delegation>, String> {
+    let canister = CANISTER_CONFIG
+        .with(|c| c.borrow().get().user_management_canister)
+        .ok_or("User management canister is not configured".to_string())?;
+
+    Call::bounded_wait(canister, "get_delegation")
+        .with_arg(delegation_id.to_string())
+        .await
+        .map_err(|e| format!("Failed to look up delegation: {}", e))?
+        .candid::<Option<Delegation>>()
+        .map_err(|e| format!("Failed to decode delegation: {}", e))
+}
+
+// Checks that the caller may use the given delegation for `scope` right now. Organization
+// delegations also require whoever granted them to still operate the organization.
+async fn resolve_delegation(
+    delegation_id: Option<String>,
+    caller: Principal,
+    scope: DelegationScope,
+) -> Result<Option<Delegation>, String> {
+    let delegation_id = match delegation_id {
+        Some(delegation_id) => delegation_id,
+        None => return Ok(None),
+    };
+
+    let delegation = fetch_delegation(&delegation_id)
+        .await?
+        .ok_or("Delegation not found".to_string())?;
+
+    if delegation.delegate != caller {
+        return Err("Delegation was not granted to the caller".to_string());
+    }
+
+    if delegation.revoked_at.is_some() {
+        return Err("Delegation has been revoked".to_string());
+    }
+
+    if delegation.expires_at <= time() {
+        return Err("Delegation has expired".to_string());
+    }
+
+    if !delegation.scopes.contains(&scope) {
+        return Err("Delegation does not cover this action".to_string());
+    }
+
+    if let DelegationSubject::Organization(organization_id) = &delegation.subject {
+        require_org_operator(organization_id, delegation.granted_by)
+            .await
+            .map_err(|_| "Delegation grantor no longer operates the organization".to_string())?;
+    }
+
+    Ok(Some(delegation))
+}
+
+// The principal a call is made for: the caller itself, the user behind a delegation, or for
+// organization delegations the member who granted it.
+fn acting_principal(caller: Principal, delegation: Option<&Delegation>) -> Principal {
+    match delegation {
+        Some(Delegation {
+            subject: DelegationSubject::User(user),
+            ..
+        }) => *user,
+        Some(delegation) => delegation.granted_by,
+        None => caller,
+    }
+}
+
+fn load_product(product_id: &str) -> Result<Product, String> {
+    PRODUCTS.with(|p| {
+        p.borrow()
+            .get(&product_id.to_string())
+            .ok_or("Product not found".to_string())
+    })
+}
+
+// Loads a product the caller may act on: either they (or the user they act for) own it, or
+// they operate the organization that does. The product is re-read after the membership
+// check, since it may have changed while the call to user_management_backend was in flight.
+async fn load_authorized_product(
+    product_id: &str,
+    caller: Principal,
+    delegation: Option<&Delegation>,
+    unauthorized: &str,
+) -> Result<Product, String> {
+    let product = load_product(product_id)?;
+
+    // Organization delegations cover every product the organization holds.
+    if let Some(Delegation {
+        subject: DelegationSubject::Organization(organization_id),
+        ..
+    }) = delegation
+    {
+        if product.owner_organization.as_ref() != Some(organization_id) {
+            return Err(unauthorized.to_string());
+        }
+        return Ok(product);
+    }
+
+    let principal = acting_principal(caller, delegation);
+    if product.current_owner == principal {
+        return Ok(product);
+    }
+
+    let organization_id = product
+        .owner_organization
+        .clone()
+        .ok_or(unauthorized.to_string())?;
+    require_org_operator(&organization_id, principal)
+        .await
+        .map_err(|_| unauthorized.to_string())?;
+
+    let product = load_product(product_id)?;
+    if product.owner_organization.as_ref() != Some(&organization_id) {
+        return Err(unauthorized.to_string());
+    }
+
+    Ok(product)
+}
+
+fn validate_certifications(certifications: &[Certification], now: u64) -> Result<(), String> {
+    if certifications.len() > MAX_CERTIFICATIONS {
+        return Err(format!(
+            "A product can have at most {} certifications",
+            MAX_CERTIFICATIONS
+        ));
+    }
+
+    for certification in certifications {
+        if certification.scheme.is_empty() || certification.certificate_number.is_empty() {
+            return Err("Certification scheme and number are required".to_string());
+        }
+
+        let fields = [
+            &certification.scheme,
+            &certification.issuer,
+            &certification.certificate_number,
+        ];
+        if fields
+            .into_iter()
+            .chain(&certification.document_hash)
+            .any(|field| field.chars().count() > MAX_CERTIFICATION_FIELD_LENGTH)
+        {
+            return Err(format!(
+                "Certification fields must be at most {} characters",
+                MAX_CERTIFICATION_FIELD_LENGTH
+            ));
+        }
+
+        if certification.valid_from > certification.valid_until {
+            return Err(format!(
+                "Certification {} is valid until before it is valid from",
+                certification.certificate_number
+            ));
+        }
+
+        if certification.valid_until <= now {
+            return Err(format!(
+                "Certification {} has expired",
+                certification.certificate_number
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Includes the expiry so a certificate renewed under the same number is warned about again.
+fn certification_alert_id(
+    owner: Principal,
+    product_id: &str,
+    certificate_number: &str,
+    valid_until: u64,
+) -> String {
+    format!(
+        "{}|{}|{}|{:020}",
+        owner.to_text(),
+        product_id,
+        certificate_number,
+        valid_until
+    )
+}
+
+// Alerts raised before the expiry was part of the key are moved to their current key, so
+// they are not raised a second time.
+fn rekey_certification_alerts() {
+    let current_id = |alert: &CertificationAlert| {
+        certification_alert_id(
+            alert.owner,
+            &alert.product_id,
+            &alert.certificate_number,
+            alert.valid_until,
+        )
+    };
+    let stale: Vec<(String, CertificationAlert)> = CERTIFICATION_ALERTS.with(|a| {
+        a.borrow()
+            .iter()
+            .filter(|(key, alert)| *key != current_id(alert))
+            .collect()
+    });
+
+    for (key, mut alert) in stale {
+        alert.id = current_id(&alert);
+        CERTIFICATION_ALERTS.with(|a| {
+            let mut alerts = a.borrow_mut();
+            alerts.remove(&key);
+            alerts.insert(alert.id.clone(), alert);
+        });
+    }
+}
+
+fn warn_expiring_certifications() {
+    let now = time();
+    let products: Vec<Product> =
+        PRODUCTS.with(|p| p.borrow().iter().map(|(_, product)| product).collect());
+
+    for product in products {
+        for certification in &product.certifications {
+            if certification.valid_until > now + CERTIFICATION_WARNING_WINDOW {
+                continue;
+            }
+
+            let alert_id = certification_alert_id(
+                product.current_owner,
+                &product.id,
+                &certification.certificate_number,
+                certification.valid_until,
+            );
+            if CERTIFICATION_ALERTS.with(|a| a.borrow().contains_key(&alert_id)) {
+                continue;
+            }
+
+            let alert = CertificationAlert {
+                id: alert_id.clone(),
+                owner: product.current_owner,
+                product_id: product.id.clone(),
+                scheme: certification.scheme.clone(),
+                certificate_number: certification.certificate_number.clone(),
+                valid_until: certification.valid_until,
+                created_at: now,
+            };
+
+            CERTIFICATION_ALERTS.with(|a| a.borrow_mut().insert(alert_id, alert));
+        }
+    }
+}
+
+#[update]
+#[allow(clippy::too_many_arguments)]
+async fn create_product(
+    name: String,
+    description: String,
+    batch_number: String,
+    expiry_date: Option<u64>,
+    price: f64,
+    quantity: u32,
+    category: String,
+    origin: String,
+    certifications: Vec<Certification>,
+    organization_id: Option<String>,
+    delegation_id: Option<String>,
 ) -> Result<Product, String> {
-    let caller = ic_cdk::caller();
+    let caller = msg_caller();
+
+    validate_certifications(&certifications, time())?;
+
+    let delegation =
+        resolve_delegation(delegation_id, caller, DelegationScope::CreateProduct).await?;
+    require_active(caller, delegation.as_ref()).await?;
+    let owner = acting_principal(caller, delegation.as_ref());
+
+    let organization_id = match delegation.as_ref().map(|d| &d.subject) {
+        Some(DelegationSubject::Organization(delegated)) => {
+            if organization_id.as_ref().is_some_and(|id| id != delegated) {
+                return Err("Delegation is for a different organization".to_string());
+            }
+            Some(delegated.clone())
+        }
+        _ => {
+            if let Some(organization_id) = &organization_id {
+                require_org_operator(organization_id, owner).await?;
+            }
+            organization_id
+        }
+    };
+
     let product_id = generate_id();
     let current_time = time();
 
@@ -172,8 +1162,8 @@ fn create_product(
         id: product_id.clone(),
         name,
         description,
-        supplier_id: caller,
-        current_owner: caller,
+        supplier_id: owner,
+        current_owner: owner,
         status: ProductStatus::Created,
         created_at: current_time,
         updated_at: current_time,
@@ -184,15 +1174,17 @@ fn create_product(
         category,
         origin: origin.clone(),
         certifications,
+        owner_organization: organization_id,
     };
 
-    PRODUCTS.with(|p| p.borrow_mut().insert(product_id.clone(), product.clone()));
+    save_product(&product);
 
     // Create tracking event
     let tracking_event = TrackingEvent {
         id: generate_id(),
         product_id: product_id.clone(),
         user_id: caller,
+        acting_for: delegation.map(|d| d.subject),
         user_role: UserRole::Supplier,
         event_type: "PRODUCT_CREATED".to_string(),
         description: "Product created by supplier".to_string(),
@@ -213,37 +1205,53 @@ fn create_product(
 }
 
 #[update]
-fn transfer_product(
+async fn transfer_product(
     product_id: String,
     to_user: Principal,
     transfer_type: String,
     notes: String,
+    delegation_id: Option<String>,
+    purchase_order_line: Option<PurchaseOrderLineRef>,
 ) -> Result<Transfer, String> {
-    let caller = ic_cdk::caller();
-    let current_time = time();
+    let caller = msg_caller();
 
-    // Check if product exists and caller is current owner
-    let mut product = PRODUCTS.with(|p| {
-        p.borrow()
-            .get(&product_id)
-            .ok_or("Product not found".to_string())
-    })?;
+    let delegation =
+        resolve_delegation(delegation_id, caller, DelegationScope::TransferProduct).await?;
+    require_active(caller, delegation.as_ref()).await?;
 
-    if product.current_owner != caller {
-        return Err("Not authorized to transfer this product".to_string());
+    // Check if product exists and caller can act for its owner
+    let mut product = load_authorized_product(
+        &product_id,
+        caller,
+        delegation.as_ref(),
+        "Not authorized to transfer this product",
+    )
+    .await?;
+
+    if in_active_shipment(&product_id) {
+        return Err("Product is part of an active shipment".to_string());
+    }
+
+    if let Some(line_ref) = &purchase_order_line {
+        record_line_shipment(line_ref, &product, to_user)?;
     }
 
+    let current_time = time();
     let transfer_id = generate_id();
     let transfer = Transfer {
         id: transfer_id.clone(),
         product_id: product_id.clone(),
-        from_user: caller,
+        from_user: product.current_owner,
         to_user,
+        initiated_by: caller,
         transfer_type: transfer_type.clone(),
         status: "PENDING".to_string(),
         initiated_at: current_time,
         completed_at: None,
         notes,
+        quantity: product.quantity,
+        purchase_order_line: purchase_order_line.clone(),
+        proof_of_delivery: None,
     };
 
     // Update product status based on transfer type
@@ -254,26 +1262,38 @@ fn transfer_product(
         _ => {}
     }
 
+    // The product leaves the organization along with the transfer.
+    let previous_organization = product.owner_organization.take();
     product.current_owner = to_user;
     product.updated_at = current_time;
 
-    PRODUCTS.with(|p| p.borrow_mut().insert(product_id.clone(), product));
+    save_product(&product);
     TRANSFERS.with(|t| t.borrow_mut().insert(transfer_id.clone(), transfer.clone()));
 
+    let mut metadata = vec![
+        ("transfer_type".to_string(), transfer_type.clone()),
+        ("to_user".to_string(), to_user.to_text()),
+    ];
+    if let Some(line_ref) = purchase_order_line {
+        metadata.push(("purchase_order_id".to_string(), line_ref.purchase_order_id));
+        metadata.push(("line_number".to_string(), line_ref.line_number.to_string()));
+    }
+    if let Some(organization_id) = previous_organization {
+        metadata.push(("organization_id".to_string(), organization_id));
+    }
+
     // Create tracking event
     let tracking_event = TrackingEvent {
         id: generate_id(),
         product_id: product_id.clone(),
         user_id: caller,
+        acting_for: delegation.map(|d| d.subject),
         user_role: UserRole::Supplier, // This should be determined dynamically
         event_type: "PRODUCT_TRANSFERRED".to_string(),
         description: format!("Product transferred via {}", transfer_type),
         location: "Unknown".to_string(),
         timestamp: current_time,
-        metadata: vec![
-            ("transfer_type".to_string(), transfer_type),
-            ("to_user".to_string(), to_user.to_text()),
-        ],
+        metadata,
     };
 
     TRACKING_EVENTS.with(|t| {
@@ -285,35 +1305,43 @@ fn transfer_product(
 }
 
 #[update]
-fn update_product_status(
+async fn update_product_status(
     product_id: String,
     new_status: ProductStatus,
     location: String,
     notes: String,
+    delegation_id: Option<String>,
 ) -> Result<Product, String> {
-    let caller = ic_cdk::caller();
-    let current_time = time();
+    let caller = msg_caller();
 
-    let mut product = PRODUCTS.with(|p| {
-        p.borrow()
-            .get(&product_id)
-            .ok_or("Product not found".to_string())
-    })?;
+    let delegation =
+        resolve_delegation(delegation_id, caller, DelegationScope::UpdateProductStatus).await?;
+    require_active(caller, delegation.as_ref()).await?;
 
-    if product.current_owner != caller {
-        return Err("Not authorized to update this product".to_string());
+    let mut product = load_authorized_product(
+        &product_id,
+        caller,
+        delegation.as_ref(),
+        "Not authorized to update this product",
+    )
+    .await?;
+    // A shipped product's status follows its shipment until the shipment ends.
+    if in_active_shipment(&product_id) {
+        return Err("Product is part of an active shipment".to_string());
     }
+    let current_time = time();
 
     product.status = new_status.clone();
     product.updated_at = current_time;
 
-    PRODUCTS.with(|p| p.borrow_mut().insert(product_id.clone(), product.clone()));
+    save_product(&product);
 
     // Create tracking event
     let tracking_event = TrackingEvent {
         id: generate_id(),
         product_id: product_id.clone(),
         user_id: caller,
+        acting_for: delegation.map(|d| d.subject),
         user_role: UserRole::Supplier, // Should be determined dynamically
         event_type: "STATUS_UPDATED".to_string(),
         description: format!("Product status updated to {:?}", new_status),
@@ -333,6 +1361,43 @@ fn update_product_status(
     Ok(product)
 }
 
+// Moves a product into or out of an organization. Moving it in requires operating the
+// target organization as well as the product.
+#[update]
+async fn set_product_organization(
+    product_id: String,
+    organization_id: Option<String>,
+) -> Result<Product, String> {
+    let caller = msg_caller();
+
+    require_active(caller, None).await?;
+    let authorized = load_authorized_product(
+        &product_id,
+        caller,
+        None,
+        "Not authorized to update this product",
+    )
+    .await?;
+    if let Some(organization_id) = &organization_id {
+        require_org_operator(organization_id, caller).await?;
+    }
+
+    // The product may have changed hands while the organization was being checked.
+    let mut product = load_product(&product_id)?;
+    if product.current_owner != authorized.current_owner
+        || product.owner_organization != authorized.owner_organization
+    {
+        return Err("Not authorized to update this product".to_string());
+    }
+
+    product.owner_organization = organization_id;
+    product.updated_at = time();
+
+    save_product(&product);
+
+    Ok(product)
+}
+
 #[query]
 fn get_product(product_id: String) -> Result<Product, String> {
     PRODUCTS.with(|p| {
@@ -353,6 +1418,146 @@ fn get_products_by_owner(owner: Principal) -> Vec<Product> {
     })
 }
 
+// Text words and facet filters are resolved through PRODUCT_INDEX; only the products they
+// select are loaded for the range filters. Results are ordered by product id. Each call reads
+// at most MAX_SEARCH_SCAN candidates after the cursor, and `total` and the facets count the
+// matches among those, so they cover every remaining match once next_cursor is None.
+#[query]
+fn search_products(query: ProductSearchQuery) -> ProductSearchResult {
+    let mut lookups: Vec<String> = search_tokens(query.text.as_deref().unwrap_or(""))
+        .into_iter()
+        .map(|token| format!("term:{}", token))
+        .collect();
+    if let Some(status) = &query.status {
+        lookups.push(format!("status:{:?}|", status));
+    }
+    if let Some(category) = &query.category {
+        lookups.push(format!("category:{}|", facet_value(category)));
+    }
+    if let Some(origin) = &query.origin {
+        lookups.push(format!("origin:{}|", facet_value(origin)));
+    }
+    if let Some(certification) = &query.certification {
+        lookups.push(format!("cert:{}|", facet_value(certification)));
+    }
+
+    let after = match &query.cursor {
+        Some(cursor) => Bound::Excluded(cursor.clone()),
+        None => Bound::Unbounded,
+    };
+
+    // One candidate past the scan limit is read to tell whether the scan was cut short.
+    let mut candidates: Vec<Product> = match lookups.split_first() {
+        Some((first, rest)) => {
+            let mut ids = index_lookup(first);
+            for prefix in rest {
+                if ids.is_empty() {
+                    break;
+                }
+                let matching = index_lookup(prefix);
+                ids.retain(|id| matching.contains(id));
+            }
+            PRODUCTS.with(|p| {
+                let products = p.borrow();
+                ids.range::<String, _>((after, Bound::Unbounded))
+                    .take(MAX_SEARCH_SCAN + 1)
+                    .filter_map(|id| products.get(id))
+                    .collect()
+            })
+        }
+        None => PRODUCTS.with(|p| {
+            p.borrow()
+                .range((after, Bound::Unbounded))
+                .take(MAX_SEARCH_SCAN + 1)
+                .map(|(_, product)| product)
+                .collect()
+        }),
+    };
+    let scan_cut_short = candidates.len() > MAX_SEARCH_SCAN;
+    candidates.truncate(MAX_SEARCH_SCAN);
+    let last_scanned = candidates.last().map(|product| product.id.clone());
+
+    let in_range = |value: u64, after: Option<u64>, before: Option<u64>| {
+        after.is_none_or(|after| value >= after) && before.is_none_or(|before| value <= before)
+    };
+
+    // Products without an expiry date never match an expiry range.
+    let filters_expiry = query.expires_after.is_some() || query.expires_before.is_some();
+
+    let matches: Vec<Product> = candidates
+        .into_iter()
+        .filter(|product| {
+            query.min_price.is_none_or(|min| product.price >= min)
+                && query.max_price.is_none_or(|max| product.price <= max)
+                && in_range(
+                    product.created_at,
+                    query.created_after,
+                    query.created_before,
+                )
+                && (!filters_expiry
+                    || product.expiry_date.is_some_and(|expiry| {
+                        in_range(expiry, query.expires_after, query.expires_before)
+                    }))
+        })
+        .collect();
+
+    let mut status = BTreeMap::new();
+    let mut category = BTreeMap::new();
+    let mut origin = BTreeMap::new();
+    let mut certification = BTreeMap::new();
+    // Counted by the same normalized values the filters match on, so each count is what
+    // filtering by that value would return.
+    for product in &matches {
+        *status.entry(format!("{:?}", product.status)).or_insert(0) += 1;
+        *category.entry(facet_value(&product.category)).or_insert(0) += 1;
+        *origin.entry(facet_value(&product.origin)).or_insert(0) += 1;
+        let schemes: BTreeSet<String> = product
+            .certifications
+            .iter()
+            .map(|c| facet_value(&c.scheme))
+            .collect();
+        for scheme in schemes {
+            *certification.entry(scheme).or_insert(0) += 1;
+        }
+    }
+
+    let total = matches.len() as u64;
+    let limit = query.limit.clamp(1, MAX_SEARCH_PAGE_SIZE) as usize;
+    let mut products = matches;
+    let next_cursor = if products.len() > limit {
+        products.truncate(limit);
+        products.last().map(|product| product.id.clone())
+    } else if scan_cut_short {
+        // The page may be short, or even empty; the next call carries on from here.
+        last_scanned
+    } else {
+        None
+    };
+
+    ProductSearchResult {
+        products,
+        total,
+        facets: ProductFacets {
+            status: status.into_iter().collect(),
+            category: category.into_iter().collect(),
+            origin: origin.into_iter().collect(),
+            certification: certification.into_iter().collect(),
+        },
+        next_cursor,
+    }
+}
+
+#[query]
+fn get_products_by_organization(organization_id: String) -> Vec<Product> {
+    PRODUCTS.with(|p| {
+        p.borrow()
+            .iter()
+            .filter(|(_, product)| product.owner_organization.as_ref() == Some(&organization_id))
+            .map(|(_, product)| product)
+            .collect()
+    })
+}
+
 #[query]
 fn get_product_tracking_history(product_id: String) -> Vec<TrackingEvent> {
     TRACKING_EVENTS.with(|t| {
@@ -382,6 +1587,15 @@ fn get_products_by_status(status: ProductStatus) -> Vec<Product> {
     })
 }
 
+#[query]
+fn get_transfer(transfer_id: String) -> Result<Transfer, String> {
+    TRANSFERS.with(|t| {
+        t.borrow()
+            .get(&transfer_id)
+            .ok_or("Transfer not found".to_string())
+    })
+}
+
 #[query]
 fn get_transfers_by_user(user: Principal) -> Vec<Transfer> {
     TRANSFERS.with(|t| {
@@ -393,9 +1607,116 @@ fn get_transfers_by_user(user: Principal) -> Vec<Transfer> {
     })
 }
 
+// Whether two users have a transfer between them that has not completed yet. Used by
+// user_management_backend to decide who may see a user's contact details, so only that
+// canister and the two users themselves may ask.
+#[query]
+fn has_active_transfer(a: Principal, b: Principal) -> Result<bool, String> {
+    let caller = msg_caller();
+    let user_management_canister =
+        CANISTER_CONFIG.with(|c| c.borrow().get().user_management_canister);
+    if caller != a && caller != b && Some(caller) != user_management_canister {
+        return Err("Not authorized to look up these transfers".to_string());
+    }
+
+    Ok(TRANSFERS.with(|t| {
+        t.borrow().iter().any(|(_, transfer)| {
+            transfer.status == "PENDING"
+                && ((transfer.from_user == a && transfer.to_user == b)
+                    || (transfer.from_user == b && transfer.to_user == a))
+        })
+    }))
+}
+
+fn validate_proof_of_delivery(proof: &ProofOfDelivery, shipped: u32) -> Result<(), String> {
+    if proof.location.trim().is_empty() {
+        return Err("Delivery location is required".to_string());
+    }
+    if proof.location.chars().count() > MAX_LOCATION_LENGTH {
+        return Err(format!(
+            "Delivery location must be at most {} characters",
+            MAX_LOCATION_LENGTH
+        ));
+    }
+    if let Some(hash) = &proof.evidence_hash {
+        validate_evidence_hash(hash)?;
+    }
+    if proof.quantity_received > shipped {
+        return Err(format!(
+            "Received quantity exceeds the {} units shipped",
+            shipped
+        ));
+    }
+
+    match proof.condition {
+        DeliveryCondition::Ok if proof.quantity_received != shipped => {
+            Err("A short delivery must be reported as short".to_string())
+        }
+        DeliveryCondition::Short if proof.quantity_received == shipped => {
+            Err("A short delivery must have received fewer units than shipped".to_string())
+        }
+        _ => Ok(()),
+    }
+}
+
+// Opens a dispute against the sender of a transfer whose delivery did not match what was sent.
+fn open_delivery_dispute(transfer: &Transfer, proof: &ProofOfDelivery) -> Dispute {
+    let current_time = time();
+    let description = match proof.condition {
+        DeliveryCondition::Damaged => format!(
+            "Delivered damaged ({} of {} units received)",
+            proof.quantity_received, transfer.quantity
+        ),
+        _ => format!(
+            "Delivered short ({} of {} units received)",
+            proof.quantity_received, transfer.quantity
+        ),
+    };
+
+    let dispute = Dispute {
+        id: generate_id(),
+        subject: DisputeSubject::Transfer(transfer.id.clone()),
+        claimant: transfer.to_user,
+        respondent: transfer.from_user,
+        product_ids: vec![transfer.product_id.clone()],
+        claims: vec![DisputeClaim {
+            submitted_by: transfer.to_user,
+            description,
+            created_at: current_time,
+        }],
+        evidence: proof
+            .evidence_hash
+            .iter()
+            .map(|hash| DisputeEvidence {
+                submitted_by: transfer.to_user,
+                hash: hash.clone(),
+                description: "Proof of delivery".to_string(),
+                submitted_at: current_time,
+            })
+            .collect(),
+        messages: vec![],
+        status: DisputeStatus::Open,
+        ruling: None,
+        created_at: current_time,
+        updated_at: current_time,
+        closed_at: None,
+        reported_at: None,
+    };
+
+    save_dispute(&dispute);
+    dispute
+}
+
+// The receiver completes a transfer by attesting to what arrived. Damaged or short deliveries
+// update the product accordingly and open a dispute with the sender.
 #[update]
-fn complete_transfer(transfer_id: String) -> Result<Transfer, String> {
-    let caller = ic_cdk::caller();
+async fn complete_transfer(
+    transfer_id: String,
+    proof: ProofOfDelivery,
+) -> Result<Transfer, String> {
+    let caller = msg_caller();
+
+    require_active(caller, None).await?;
     let current_time = time();
 
     let mut transfer = TRANSFERS.with(|t| {
@@ -408,22 +1729,62 @@ fn complete_transfer(transfer_id: String) -> Result<Transfer, String> {
         return Err("Not authorized to complete this transfer".to_string());
     }
 
+    if transfer.status != "PENDING" {
+        return Err("Transfer is not pending".to_string());
+    }
+
+    validate_proof_of_delivery(&proof, transfer.quantity)?;
+    let mut product = load_product(&transfer.product_id)?;
+
     transfer.status = "COMPLETED".to_string();
     transfer.completed_at = Some(current_time);
+    transfer.proof_of_delivery = Some(proof.clone());
+
+    if let Some(line_ref) = &transfer.purchase_order_line {
+        record_line_receipt(line_ref, proof.quantity_received);
+    }
 
     TRANSFERS.with(|t| t.borrow_mut().insert(transfer_id.clone(), transfer.clone()));
 
+    let mut metadata = vec![
+        ("transfer_id".to_string(), transfer_id),
+        ("condition".to_string(), format!("{:?}", proof.condition)),
+        (
+            "quantity_received".to_string(),
+            proof.quantity_received.to_string(),
+        ),
+    ];
+    if let Some(hash) = &proof.evidence_hash {
+        metadata.push(("evidence_hash".to_string(), hash.clone()));
+    }
+
+    if proof.condition != DeliveryCondition::Ok {
+        // Once the receiver has passed the product on, its record describes a later holder.
+        if product.current_owner == transfer.to_user {
+            if proof.condition == DeliveryCondition::Damaged {
+                product.status = ProductStatus::Damaged;
+            }
+            product.quantity = proof.quantity_received;
+            product.updated_at = current_time;
+            save_product(&product);
+        }
+
+        let dispute = open_delivery_dispute(&transfer, &proof);
+        metadata.push(("dispute_id".to_string(), dispute.id));
+    }
+
     // Create tracking event
     let tracking_event = TrackingEvent {
         id: generate_id(),
         product_id: transfer.product_id.clone(),
         user_id: caller,
+        acting_for: None,
         user_role: UserRole::Supplier, // Should be determined dynamically
         event_type: "TRANSFER_COMPLETED".to_string(),
         description: "Product transfer completed".to_string(),
-        location: "Unknown".to_string(),
+        location: proof.location,
         timestamp: current_time,
-        metadata: vec![("transfer_id".to_string(), transfer_id)],
+        metadata,
     };
 
     TRACKING_EVENTS.with(|t| {
@@ -434,13 +1795,1503 @@ fn complete_transfer(transfer_id: String) -> Result<Transfer, String> {
     Ok(transfer)
 }
 
-#[query]
-fn get_statistics() -> (u64, u64, u64) {
-    let products_count = PRODUCTS.with(|p| p.borrow().len());
-    let tracking_events_count = TRACKING_EVENTS.with(|t| t.borrow().len());
-    let transfers_count = TRANSFERS.with(|t| t.borrow().len());
+fn load_dispute(dispute_id: &String) -> Result<Dispute, String> {
+    DISPUTES.with(|d| {
+        d.borrow()
+            .get(dispute_id)
+            .ok_or("Dispute not found".to_string())
+    })
+}
 
-    (products_count, tracking_events_count, transfers_count)
+fn save_dispute(dispute: &Dispute) {
+    DISPUTES.with(|d| d.borrow_mut().insert(dispute.id.clone(), dispute.clone()));
 }
 
-ic_cdk::export_candid!();
+fn is_dispute_party(dispute: &Dispute, principal: Principal) -> bool {
+    dispute.claimant == principal || dispute.respondent == principal
+}
+
+// Arbitrators are users registered with the Admin role in user_management_backend.
+async fn is_arbitrator(principal: Principal) -> bool {
+    fetch_user(principal)
+        .await
+        .is_ok_and(|account| is_arbitrator_account(&account))
+}
+
+fn is_arbitrator_account(account: &User) -> bool {
+    account.role == UserRole::Admin && account.status == AccountStatus::Active
+}
+
+// The parties to a transfer or shipment, and the products it covers.
+fn dispute_subject_parties(
+    subject: &DisputeSubject,
+) -> Result<(Vec<Principal>, Vec<String>), String> {
+    match subject {
+        DisputeSubject::Transfer(transfer_id) => {
+            let transfer = TRANSFERS.with(|t| {
+                t.borrow()
+                    .get(transfer_id)
+                    .ok_or("Transfer not found".to_string())
+            })?;
+            Ok((
+                vec![transfer.from_user, transfer.to_user],
+                vec![transfer.product_id],
+            ))
+        }
+        DisputeSubject::Shipment(shipment_id) => {
+            let shipment = load_shipment(shipment_id)?;
+            let mut parties = vec![shipment.shipper, shipment.consignee];
+            parties.extend(shipment.legs.iter().map(|leg| leg.carrier));
+            Ok((parties, shipment.product_ids))
+        }
+    }
+}
+
+fn validate_evidence_hash(hash: &str) -> Result<(), String> {
+    if hash.trim().is_empty() {
+        return Err("Evidence hash cannot be empty".to_string());
+    }
+    if hash.chars().count() > MAX_EVIDENCE_HASH_LENGTH {
+        return Err(format!(
+            "Evidence hash must be at most {} characters",
+            MAX_EVIDENCE_HASH_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+fn validate_dispute_text(text: &str, field: &str) -> Result<(), String> {
+    if text.trim().is_empty() {
+        return Err(format!("{} is required", field));
+    }
+    if text.chars().count() > MAX_DISPUTE_TEXT_LENGTH {
+        return Err(format!(
+            "{} must be at most {} characters",
+            field, MAX_DISPUTE_TEXT_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+fn load_open_dispute_as_party(dispute_id: &String, caller: Principal) -> Result<Dispute, String> {
+    let dispute = load_dispute(dispute_id)?;
+    if !is_dispute_party(&dispute, caller) {
+        return Err("Not a party to this dispute".to_string());
+    }
+    if dispute.status != DisputeStatus::Open {
+        return Err("Dispute is closed".to_string());
+    }
+    Ok(dispute)
+}
+
+// Sends a closed dispute to rating_backend and reporting_backend. Both record closures by
+// dispute id, so resending after a partial failure is harmless.
+async fn report_dispute_closure(dispute: &Dispute) -> Result<(), String> {
+    let config = CANISTER_CONFIG.with(|c| c.borrow().get().clone());
+    let closure = DisputeClosure {
+        dispute_id: dispute.id.clone(),
+        claimant: dispute.claimant,
+        respondent: dispute.respondent,
+        outcome: dispute.ruling.as_ref().map(|ruling| ruling.outcome.clone()),
+        opened_at: dispute.created_at,
+        closed_at: dispute.closed_at.unwrap_or(dispute.updated_at),
+    };
+
+    let canisters = [
+        ("Rating", config.rating_canister),
+        ("Reporting", config.reporting_canister),
+    ];
+    for (name, canister) in canisters {
+        let canister = canister.ok_or(format!("{} canister is not configured", name))?;
+        Call::bounded_wait(canister, "record_dispute_closure")
+            .with_arg(&closure)
+            .await
+            .map_err(|e| format!("Failed to report dispute: {}", e))?
+            .candid::<Result<(), String>>()
+            .map_err(|e| format!("Failed to decode dispute report: {}", e))??;
+    }
+
+    if let Ok(mut dispute) = load_dispute(&dispute.id) {
+        dispute.reported_at = Some(time());
+        save_dispute(&dispute);
+    }
+
+    Ok(())
+}
+
+async fn report_closed_disputes() {
+    let unreported: Vec<Dispute> = DISPUTES.with(|d| {
+        d.borrow()
+            .iter()
+            .filter(|(_, dispute)| dispute.closed_at.is_some() && dispute.reported_at.is_none())
+            .map(|(_, dispute)| dispute)
+            .collect()
+    });
+
+    for dispute in unreported {
+        if report_dispute_closure(&dispute).await.is_err() {
+            // The other canisters are unreachable or unconfigured; try again next interval.
+            break;
+        }
+    }
+}
+
+// Either party to a transfer or shipment can open a dispute against another party to it.
+#[update]
+async fn open_dispute(
+    subject: DisputeSubject,
+    respondent: Principal,
+    claim: String,
+    evidence_hashes: Vec<String>,
+) -> Result<Dispute, String> {
+    let caller = msg_caller();
+
+    validate_dispute_text(&claim, "Claim")?;
+    if evidence_hashes.len() > MAX_DISPUTE_EVIDENCE {
+        return Err(format!(
+            "A dispute can have at most {} pieces of evidence",
+            MAX_DISPUTE_EVIDENCE
+        ));
+    }
+    for hash in &evidence_hashes {
+        validate_evidence_hash(hash)?;
+    }
+
+    require_active(caller, None).await?;
+
+    let (parties, product_ids) = dispute_subject_parties(&subject)?;
+    if !parties.contains(&caller) || !parties.contains(&respondent) || caller == respondent {
+        return Err(
+            "Disputes can only be opened between parties to the transfer or shipment".to_string(),
+        );
+    }
+
+    let already_open = DISPUTES.with(|d| {
+        d.borrow().iter().any(|(_, dispute)| {
+            dispute.status == DisputeStatus::Open
+                && dispute.subject == subject
+                && dispute.claimant == caller
+                && dispute.respondent == respondent
+        })
+    });
+    if already_open {
+        return Err("An open dispute already exists for this claim".to_string());
+    }
+
+    let current_time = time();
+    let dispute = Dispute {
+        id: generate_id(),
+        subject,
+        claimant: caller,
+        respondent,
+        product_ids,
+        claims: vec![DisputeClaim {
+            submitted_by: caller,
+            description: claim,
+            created_at: current_time,
+        }],
+        evidence: evidence_hashes
+            .into_iter()
+            .map(|hash| DisputeEvidence {
+                submitted_by: caller,
+                hash,
+                description: String::new(),
+                submitted_at: current_time,
+            })
+            .collect(),
+        messages: vec![],
+        status: DisputeStatus::Open,
+        ruling: None,
+        created_at: current_time,
+        updated_at: current_time,
+        closed_at: None,
+        reported_at: None,
+    };
+
+    save_dispute(&dispute);
+    Ok(dispute)
+}
+
+// Claims can be added by either party, e.g. a counter-claim by the respondent.
+#[update]
+fn add_dispute_claim(dispute_id: String, description: String) -> Result<Dispute, String> {
+    let caller = msg_caller();
+    validate_dispute_text(&description, "Claim")?;
+
+    let mut dispute = load_open_dispute_as_party(&dispute_id, caller)?;
+    if dispute.claims.len() >= MAX_DISPUTE_CLAIMS {
+        return Err(format!(
+            "A dispute can have at most {} claims",
+            MAX_DISPUTE_CLAIMS
+        ));
+    }
+
+    let current_time = time();
+    dispute.claims.push(DisputeClaim {
+        submitted_by: caller,
+        description,
+        created_at: current_time,
+    });
+    dispute.updated_at = current_time;
+
+    save_dispute(&dispute);
+    Ok(dispute)
+}
+
+#[update]
+fn add_dispute_evidence(
+    dispute_id: String,
+    hash: String,
+    description: String,
+) -> Result<Dispute, String> {
+    let caller = msg_caller();
+    validate_evidence_hash(&hash)?;
+    if description.chars().count() > MAX_DISPUTE_TEXT_LENGTH {
+        return Err(format!(
+            "Description must be at most {} characters",
+            MAX_DISPUTE_TEXT_LENGTH
+        ));
+    }
+
+    let mut dispute = load_open_dispute_as_party(&dispute_id, caller)?;
+    if dispute.evidence.len() >= MAX_DISPUTE_EVIDENCE {
+        return Err(format!(
+            "A dispute can have at most {} pieces of evidence",
+            MAX_DISPUTE_EVIDENCE
+        ));
+    }
+
+    let current_time = time();
+    dispute.evidence.push(DisputeEvidence {
+        submitted_by: caller,
+        hash,
+        description,
+        submitted_at: current_time,
+    });
+    dispute.updated_at = current_time;
+
+    save_dispute(&dispute);
+    Ok(dispute)
+}
+
+// The message thread is open to both parties and to arbitrators.
+#[update]
+async fn post_dispute_message(dispute_id: String, body: String) -> Result<Dispute, String> {
+    let caller = msg_caller();
+    validate_dispute_text(&body, "Message")?;
+
+    let dispute = load_dispute(&dispute_id)?;
+    if !is_dispute_party(&dispute, caller) && !is_arbitrator(caller).await {
+        return Err("Not a party to this dispute".to_string());
+    }
+
+    // Re-read, since the dispute may have changed while the arbitrator check was in flight.
+    let mut dispute = load_dispute(&dispute_id)?;
+    if dispute.status != DisputeStatus::Open {
+        return Err("Dispute is closed".to_string());
+    }
+    if dispute.messages.len() >= MAX_DISPUTE_MESSAGES {
+        return Err(format!(
+            "A dispute can have at most {} messages",
+            MAX_DISPUTE_MESSAGES
+        ));
+    }
+
+    let current_time = time();
+    dispute.messages.push(DisputeMessage {
+        author: caller,
+        body,
+        sent_at: current_time,
+    });
+    dispute.updated_at = current_time;
+
+    save_dispute(&dispute);
+    Ok(dispute)
+}
+
+#[update]
+async fn withdraw_dispute(dispute_id: String) -> Result<Dispute, String> {
+    let caller = msg_caller();
+    let mut dispute = load_dispute(&dispute_id)?;
+
+    if dispute.claimant != caller {
+        return Err("Only the claimant can withdraw this dispute".to_string());
+    }
+    if dispute.status != DisputeStatus::Open {
+        return Err("Dispute is closed".to_string());
+    }
+
+    let current_time = time();
+    dispute.status = DisputeStatus::Withdrawn;
+    dispute.updated_at = current_time;
+    dispute.closed_at = Some(current_time);
+    save_dispute(&dispute);
+
+    // A failed report is retried by the timer; the withdrawal itself stands.
+    let _ = report_dispute_closure(&dispute).await;
+
+    load_dispute(&dispute_id)
+}
+
+// Arbitrators close a dispute with a ruling, which may move the disputed products to a new
+// status or back to one of the parties.
+#[update]
+async fn resolve_dispute(
+    dispute_id: String,
+    ruling: DisputeRulingInput,
+) -> Result<Dispute, String> {
+    let caller = msg_caller();
+    validate_dispute_text(&ruling.notes, "Ruling notes")?;
+
+    let arbitrator = match fetch_user(caller).await {
+        Ok(account) if is_arbitrator_account(&account) => account,
+        _ => return Err("Only arbitrators can resolve disputes".to_string()),
+    };
+
+    let mut dispute = load_dispute(&dispute_id)?;
+    if dispute.status != DisputeStatus::Open {
+        return Err("Dispute is closed".to_string());
+    }
+    if let Some(new_owner) = ruling.new_owner {
+        if !is_dispute_party(&dispute, new_owner) {
+            return Err("Products can only be awarded to a party to the dispute".to_string());
+        }
+    }
+
+    // Every product is checked before any is changed, so a ruling applies in full or not at
+    // all. A ruling only changes products still held by the parties, and not while goods are
+    // on the road.
+    let changes_products = ruling.product_status.is_some() || ruling.new_owner.is_some();
+    let mut products = vec![];
+    for product_id in &dispute.product_ids {
+        let product = load_product(product_id)?;
+        if changes_products {
+            if !is_dispute_party(&dispute, product.current_owner) {
+                return Err(format!(
+                    "Product {} is no longer held by a party to the dispute",
+                    product_id
+                ));
+            }
+            if in_active_shipment(product_id) {
+                return Err(format!(
+                    "Product {} is part of an active shipment",
+                    product_id
+                ));
+            }
+        }
+        products.push(product);
+    }
+
+    let current_time = time();
+    for mut product in products {
+        if let Some(status) = &ruling.product_status {
+            product.status = status.clone();
+        }
+        if let Some(new_owner) = ruling.new_owner {
+            product.current_owner = new_owner;
+            product.owner_organization = None;
+        }
+        product.updated_at = current_time;
+        save_product(&product);
+
+        let tracking_event = TrackingEvent {
+            id: format!("{}-{}-{}", current_time, caller.to_text(), product.id),
+            product_id: product.id,
+            user_id: caller,
+            acting_for: None,
+            user_role: arbitrator.role.clone(),
+            event_type: "DISPUTE_RESOLVED".to_string(),
+            description: format!("Dispute resolved: {:?}", ruling.outcome),
+            location: "Unknown".to_string(),
+            timestamp: current_time,
+            metadata: vec![("dispute_id".to_string(), dispute_id.clone())],
+        };
+
+        TRACKING_EVENTS.with(|t| {
+            t.borrow_mut()
+                .insert(tracking_event.id.clone(), tracking_event)
+        });
+    }
+
+    dispute.status = DisputeStatus::Resolved;
+    dispute.ruling = Some(DisputeRuling {
+        arbitrator: caller,
+        outcome: ruling.outcome,
+        notes: ruling.notes,
+        product_status: ruling.product_status,
+        new_owner: ruling.new_owner,
+        ruled_at: current_time,
+    });
+    dispute.updated_at = current_time;
+    dispute.closed_at = Some(current_time);
+    save_dispute(&dispute);
+
+    // A failed report is retried by the timer; the ruling itself stands.
+    let _ = report_dispute_closure(&dispute).await;
+
+    load_dispute(&dispute_id)
+}
+
+#[query(composite = true)]
+async fn get_dispute(dispute_id: String) -> Result<Dispute, String> {
+    let caller = msg_caller();
+    let dispute = load_dispute(&dispute_id)?;
+
+    if !is_dispute_party(&dispute, caller) && !is_arbitrator(caller).await {
+        return Err("Not authorized to view this dispute".to_string());
+    }
+
+    Ok(dispute)
+}
+
+#[query]
+fn get_my_disputes() -> Vec<Dispute> {
+    let caller = msg_caller();
+
+    DISPUTES.with(|d| {
+        d.borrow()
+            .iter()
+            .filter(|(_, dispute)| is_dispute_party(dispute, caller))
+            .map(|(_, dispute)| dispute)
+            .collect()
+    })
+}
+
+// The arbitration queue.
+#[query(composite = true)]
+async fn get_open_disputes() -> Result<Vec<Dispute>, String> {
+    if !is_arbitrator(msg_caller()).await {
+        return Err("Only arbitrators can list open disputes".to_string());
+    }
+
+    Ok(DISPUTES.with(|d| {
+        d.borrow()
+            .iter()
+            .filter(|(_, dispute)| dispute.status == DisputeStatus::Open)
+            .map(|(_, dispute)| dispute)
+            .collect()
+    }))
+}
+
+#[query]
+fn get_certification_alerts() -> Vec<CertificationAlert> {
+    let caller = msg_caller();
+    let prefix = format!("{}|", caller.to_text());
+
+    CERTIFICATION_ALERTS.with(|a| {
+        a.borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, alert)| alert)
+            .collect()
+    })
+}
+
+fn load_purchase_order(purchase_order_id: &String) -> Result<PurchaseOrder, String> {
+    PURCHASE_ORDERS.with(|o| {
+        o.borrow()
+            .get(purchase_order_id)
+            .ok_or("Purchase order not found".to_string())
+    })
+}
+
+fn save_purchase_order(order: &PurchaseOrder) {
+    PURCHASE_ORDERS.with(|o| o.borrow_mut().insert(order.id.clone(), order.clone()));
+}
+
+fn load_party_purchase_order(
+    purchase_order_id: &String,
+    caller: Principal,
+) -> Result<PurchaseOrder, String> {
+    let order = load_purchase_order(purchase_order_id)?;
+    if order.buyer != caller && order.supplier != caller {
+        return Err("Not authorized to view this purchase order".to_string());
+    }
+    Ok(order)
+}
+
+// Accepted orders move to partially fulfilled, fulfilled and finally received as transfers
+// against their lines are shipped and completed.
+fn refresh_purchase_order_status(order: &mut PurchaseOrder) {
+    if !matches!(
+        order.status,
+        PurchaseOrderStatus::Accepted
+            | PurchaseOrderStatus::PartiallyFulfilled
+            | PurchaseOrderStatus::Fulfilled
+    ) {
+        return;
+    }
+
+    order.status = if order
+        .lines
+        .iter()
+        .all(|line| line.quantity_received >= line.quantity_ordered)
+    {
+        PurchaseOrderStatus::Received
+    } else if order
+        .lines
+        .iter()
+        .all(|line| line.quantity_shipped >= line.quantity_ordered)
+    {
+        PurchaseOrderStatus::Fulfilled
+    } else if order.lines.iter().any(|line| line.quantity_shipped > 0) {
+        PurchaseOrderStatus::PartiallyFulfilled
+    } else {
+        PurchaseOrderStatus::Accepted
+    };
+}
+
+// Books a product against an order line as it is transferred from the supplier to the buyer.
+fn record_line_shipment(
+    line_ref: &PurchaseOrderLineRef,
+    product: &Product,
+    to_user: Principal,
+) -> Result<(), String> {
+    let mut order = load_purchase_order(&line_ref.purchase_order_id)?;
+
+    if !matches!(
+        order.status,
+        PurchaseOrderStatus::Accepted | PurchaseOrderStatus::PartiallyFulfilled
+    ) {
+        return Err("Purchase order is not open for fulfilment".to_string());
+    }
+    if product.current_owner != order.supplier || to_user != order.buyer {
+        return Err("Transfer must go from the order's supplier to its buyer".to_string());
+    }
+
+    let line = order
+        .lines
+        .iter_mut()
+        .find(|line| line.line_number == line_ref.line_number)
+        .ok_or("Purchase order line not found".to_string())?;
+
+    if facet_value(&product.category) != facet_value(&line.category) {
+        return Err(format!(
+            "Product category {} does not match line {}'s category {}",
+            product.category, line.line_number, line.category
+        ));
+    }
+
+    let outstanding = line.quantity_ordered - line.quantity_shipped;
+    if product.quantity == 0 || product.quantity > outstanding {
+        return Err(format!(
+            "Line {} has {} units outstanding",
+            line.line_number, outstanding
+        ));
+    }
+
+    line.quantity_shipped += product.quantity;
+    // Whatever is still outstanding after this shipment stays on backorder.
+    line.quantity_backordered = line
+        .quantity_backordered
+        .min(line.quantity_ordered - line.quantity_shipped);
+    if line.quantity_backordered == 0 {
+        line.backorder_expected_at = None;
+    }
+
+    refresh_purchase_order_status(&mut order);
+    order.updated_at = time();
+    save_purchase_order(&order);
+
+    Ok(())
+}
+
+fn record_line_receipt(line_ref: &PurchaseOrderLineRef, quantity: u32) {
+    if let Ok(mut order) = load_purchase_order(&line_ref.purchase_order_id) {
+        if let Some(line) = order
+            .lines
+            .iter_mut()
+            .find(|line| line.line_number == line_ref.line_number)
+        {
+            line.quantity_received += quantity;
+        }
+
+        refresh_purchase_order_status(&mut order);
+        order.updated_at = time();
+        save_purchase_order(&order);
+    }
+}
+
+// Raised by a warehouse or retailer against a supplier. The supplier has to accept the order
+// before any transfer can fulfil it.
+#[update]
+async fn create_purchase_order(
+    supplier: Principal,
+    lines: Vec<PurchaseOrderLineInput>,
+    notes: String,
+) -> Result<PurchaseOrder, String> {
+    let caller = msg_caller();
+
+    if lines.is_empty() || lines.len() > MAX_PURCHASE_ORDER_LINES {
+        return Err(format!(
+            "A purchase order must have between 1 and {} lines",
+            MAX_PURCHASE_ORDER_LINES
+        ));
+    }
+    if notes.chars().count() > MAX_PURCHASE_ORDER_NOTES_LENGTH {
+        return Err(format!(
+            "Notes must be at most {} characters",
+            MAX_PURCHASE_ORDER_NOTES_LENGTH
+        ));
+    }
+    for line in &lines {
+        if line.description.trim().is_empty() {
+            return Err("Line description is required".to_string());
+        }
+        if line.description.chars().count() > MAX_PURCHASE_ORDER_LINE_TEXT_LENGTH
+            || line.category.chars().count() > MAX_PURCHASE_ORDER_LINE_TEXT_LENGTH
+        {
+            return Err(format!(
+                "Line description and category must be at most {} characters",
+                MAX_PURCHASE_ORDER_LINE_TEXT_LENGTH
+            ));
+        }
+        if line.quantity == 0 {
+            return Err("Line quantity must be greater than zero".to_string());
+        }
+        if !line.unit_price.is_finite() || line.unit_price < 0.0 {
+            return Err("Line unit price must be a non-negative number".to_string());
+        }
+    }
+
+    let buyer = fetch_user(caller).await?;
+    if buyer.status != AccountStatus::Active {
+        return Err(format!("Account {} is not active", caller.to_text()));
+    }
+    if !matches!(buyer.role, UserRole::Warehouse | UserRole::Retailer) {
+        return Err("Only warehouses and retailers can raise purchase orders".to_string());
+    }
+
+    let supplier_user = fetch_user(supplier).await?;
+    if supplier_user.role != UserRole::Supplier {
+        return Err("Purchase orders must be raised against a supplier".to_string());
+    }
+    if supplier_user.status != AccountStatus::Active {
+        return Err(format!("Account {} is not active", supplier.to_text()));
+    }
+
+    let current_time = time();
+    let order = PurchaseOrder {
+        id: generate_id(),
+        buyer: caller,
+        supplier,
+        lines: lines
+            .into_iter()
+            .zip(1..)
+            .map(|(line, line_number)| PurchaseOrderLine {
+                line_number,
+                description: line.description,
+                category: line.category,
+                quantity_ordered: line.quantity,
+                unit_price: line.unit_price,
+                quantity_shipped: 0,
+                quantity_received: 0,
+                quantity_backordered: 0,
+                backorder_expected_at: None,
+            })
+            .collect(),
+        status: PurchaseOrderStatus::Submitted,
+        notes,
+        created_at: current_time,
+        updated_at: current_time,
+    };
+
+    save_purchase_order(&order);
+    Ok(order)
+}
+
+#[update]
+async fn respond_to_purchase_order(
+    purchase_order_id: String,
+    accept: bool,
+) -> Result<PurchaseOrder, String> {
+    let caller = msg_caller();
+    require_active(caller, None).await?;
+
+    let mut order = load_purchase_order(&purchase_order_id)?;
+    if order.supplier != caller {
+        return Err("Only the supplier can respond to this purchase order".to_string());
+    }
+    if order.status != PurchaseOrderStatus::Submitted {
+        return Err("Purchase order has already been responded to".to_string());
+    }
+
+    order.status = if accept {
+        PurchaseOrderStatus::Accepted
+    } else {
+        PurchaseOrderStatus::Rejected
+    };
+    order.updated_at = time();
+
+    save_purchase_order(&order);
+    Ok(order)
+}
+
+// Buyers can cancel an order until the first unit ships against it.
+#[update]
+fn cancel_purchase_order(purchase_order_id: String) -> Result<PurchaseOrder, String> {
+    let caller = msg_caller();
+    let mut order = load_purchase_order(&purchase_order_id)?;
+
+    if order.buyer != caller {
+        return Err("Only the buyer can cancel this purchase order".to_string());
+    }
+    if !matches!(
+        order.status,
+        PurchaseOrderStatus::Submitted | PurchaseOrderStatus::Accepted
+    ) {
+        return Err("Purchase order can no longer be cancelled".to_string());
+    }
+
+    order.status = PurchaseOrderStatus::Cancelled;
+    order.updated_at = time();
+
+    save_purchase_order(&order);
+    Ok(order)
+}
+
+// The supplier declares how much of a line's outstanding quantity will ship later, and when.
+// A quantity of zero clears the backorder.
+#[update]
+async fn backorder_purchase_order_line(
+    purchase_order_id: String,
+    line_number: u32,
+    quantity: u32,
+    expected_at: Option<u64>,
+) -> Result<PurchaseOrder, String> {
+    let caller = msg_caller();
+    require_active(caller, None).await?;
+
+    let mut order = load_purchase_order(&purchase_order_id)?;
+    if order.supplier != caller {
+        return Err("Only the supplier can backorder this purchase order".to_string());
+    }
+    if !matches!(
+        order.status,
+        PurchaseOrderStatus::Accepted | PurchaseOrderStatus::PartiallyFulfilled
+    ) {
+        return Err("Purchase order is not open for fulfilment".to_string());
+    }
+
+    let line = order
+        .lines
+        .iter_mut()
+        .find(|line| line.line_number == line_number)
+        .ok_or("Purchase order line not found".to_string())?;
+
+    let outstanding = line.quantity_ordered - line.quantity_shipped;
+    if quantity > outstanding {
+        return Err(format!(
+            "Line {} has {} units outstanding",
+            line.line_number, outstanding
+        ));
+    }
+
+    line.quantity_backordered = quantity;
+    line.backorder_expected_at = if quantity > 0 { expected_at } else { None };
+    order.updated_at = time();
+
+    save_purchase_order(&order);
+    Ok(order)
+}
+
+#[query]
+fn get_purchase_order(purchase_order_id: String) -> Result<PurchaseOrder, String> {
+    load_party_purchase_order(&purchase_order_id, msg_caller())
+}
+
+// Orders the caller has raised or received, optionally narrowed down to one status.
+#[query]
+fn get_my_purchase_orders(status: Option<PurchaseOrderStatus>) -> Vec<PurchaseOrder> {
+    let caller = msg_caller();
+
+    PURCHASE_ORDERS.with(|o| {
+        o.borrow()
+            .iter()
+            .filter(|(_, order)| order.buyer == caller || order.supplier == caller)
+            .filter(|(_, order)| status.as_ref().is_none_or(|status| &order.status == status))
+            .map(|(_, order)| order)
+            .collect()
+    })
+}
+
+// Open orders of the caller's with at least one line on backorder.
+#[query]
+fn get_my_backorders() -> Vec<PurchaseOrder> {
+    get_my_purchase_orders(None)
+        .into_iter()
+        .filter(|order| order.lines.iter().any(|line| line.quantity_backordered > 0))
+        .collect()
+}
+
+// The transfers fulfilling an order, for reconciling shipped and received quantities.
+#[query]
+fn get_purchase_order_transfers(purchase_order_id: String) -> Result<Vec<Transfer>, String> {
+    load_party_purchase_order(&purchase_order_id, msg_caller())?;
+
+    Ok(TRANSFERS.with(|t| {
+        t.borrow()
+            .iter()
+            .filter(|(_, transfer)| {
+                transfer
+                    .purchase_order_line
+                    .as_ref()
+                    .is_some_and(|line_ref| line_ref.purchase_order_id == purchase_order_id)
+            })
+            .map(|(_, transfer)| transfer)
+            .collect()
+    }))
+}
+
+fn in_active_shipment(product_id: &String) -> bool {
+    SHIPMENTS.with(|s| {
+        s.borrow().iter().any(|(_, shipment)| {
+            matches!(
+                shipment.status,
+                ShipmentStatus::Planned | ShipmentStatus::InTransit
+            ) && shipment.product_ids.contains(product_id)
+        })
+    })
+}
+
+fn load_shipment(shipment_id: &String) -> Result<Shipment, String> {
+    SHIPMENTS.with(|s| {
+        s.borrow()
+            .get(shipment_id)
+            .ok_or("Shipment not found".to_string())
+    })
+}
+
+// Emits a tracking event for every product in the shipment.
+fn record_shipment_event(
+    shipment: &Shipment,
+    leg_index: usize,
+    user_role: UserRole,
+    event_type: &str,
+    description: String,
+    location: String,
+) {
+    let caller = msg_caller();
+    let current_time = time();
+
+    for product_id in &shipment.product_ids {
+        let tracking_event = TrackingEvent {
+            id: format!("{}-{}-{}", current_time, caller.to_text(), product_id),
+            product_id: product_id.clone(),
+            user_id: caller,
+            acting_for: None,
+            user_role: user_role.clone(),
+            event_type: event_type.to_string(),
+            description: description.clone(),
+            location: location.clone(),
+            timestamp: current_time,
+            metadata: vec![
+                ("shipment_id".to_string(), shipment.id.clone()),
+                ("leg".to_string(), (leg_index + 1).to_string()),
+            ],
+        };
+
+        TRACKING_EVENTS.with(|t| {
+            t.borrow_mut()
+                .insert(tracking_event.id.clone(), tracking_event)
+        });
+    }
+}
+
+fn set_shipment_product_status(
+    shipment: &Shipment,
+    status: ProductStatus,
+    owner: Option<Principal>,
+) {
+    for product_id in &shipment.product_ids {
+        if let Ok(mut product) = load_product(product_id) {
+            product.status = status.clone();
+            if let Some(owner) = owner {
+                // Delivered products leave the shipper's organization, as with transfers.
+                product.current_owner = owner;
+                product.owner_organization = None;
+            }
+            product.updated_at = time();
+            save_product(&product);
+        }
+    }
+}
+
+#[update]
+async fn create_shipment(
+    product_ids: Vec<String>,
+    consignee: Principal,
+    legs: Vec<ShipmentLegInput>,
+) -> Result<Shipment, String> {
+    let caller = msg_caller();
+
+    if product_ids.is_empty() || product_ids.len() > MAX_SHIPMENT_PRODUCTS {
+        return Err(format!(
+            "A shipment must contain between 1 and {} products",
+            MAX_SHIPMENT_PRODUCTS
+        ));
+    }
+    if legs.is_empty() || legs.len() > MAX_SHIPMENT_LEGS {
+        return Err(format!(
+            "A shipment must have between 1 and {} legs",
+            MAX_SHIPMENT_LEGS
+        ));
+    }
+    if product_ids.iter().collect::<BTreeSet<_>>().len() != product_ids.len() {
+        return Err("A shipment cannot contain the same product twice".to_string());
+    }
+    if legs.iter().any(|leg| {
+        leg.origin.chars().count() > MAX_LOCATION_LENGTH
+            || leg.destination.chars().count() > MAX_LOCATION_LENGTH
+    }) {
+        return Err(format!(
+            "Leg locations must be at most {} characters",
+            MAX_LOCATION_LENGTH
+        ));
+    }
+    if legs
+        .windows(2)
+        .any(|pair| pair[0].destination != pair[1].origin)
+    {
+        return Err("Each leg must start where the previous one ends".to_string());
+    }
+
+    let shipper = require_active(caller, None).await?;
+
+    let parties: BTreeSet<Principal> = std::iter::once(consignee)
+        .chain(legs.iter().map(|leg| leg.carrier))
+        .collect();
+    for party in parties {
+        let account = fetch_user(party)
+            .await
+            .map_err(|e| format!("Consignee or carrier {}: {}", party.to_text(), e))?;
+        if account.status != AccountStatus::Active {
+            return Err(format!("Account {} is not active", party.to_text()));
+        }
+    }
+
+    let mut authorized = vec![];
+    for product_id in &product_ids {
+        let product = load_authorized_product(
+            product_id,
+            caller,
+            None,
+            "Not authorized to ship this product",
+        )
+        .await?;
+        authorized.push(product);
+    }
+
+    // Products may have changed during the membership checks above.
+    for product in &authorized {
+        if load_product(&product.id)?.updated_at != product.updated_at {
+            return Err(format!(
+                "Product {} changed while the shipment was being created",
+                product.id
+            ));
+        }
+        if in_active_shipment(&product.id) {
+            return Err(format!(
+                "Product {} is already part of an active shipment",
+                product.id
+            ));
+        }
+    }
+
+    let current_time = time();
+    let shipment = Shipment {
+        id: generate_id(),
+        shipper: caller,
+        consignee,
+        product_ids,
+        legs: legs
+            .into_iter()
+            .map(|leg| ShipmentLeg {
+                origin: leg.origin,
+                destination: leg.destination,
+                carrier: leg.carrier,
+                eta: leg.eta,
+                status: LegStatus::Planned,
+                picked_up_at: None,
+                delivered_at: None,
+            })
+            .collect(),
+        status: ShipmentStatus::Planned,
+        created_at: current_time,
+        updated_at: current_time,
+    };
+
+    SHIPMENTS.with(|s| s.borrow_mut().insert(shipment.id.clone(), shipment.clone()));
+    record_shipment_event(
+        &shipment,
+        0,
+        shipper.role,
+        "SHIPMENT_CREATED",
+        "Product added to a shipment".to_string(),
+        shipment.legs[0].origin.clone(),
+    );
+
+    Ok(shipment)
+}
+
+// Confirms that a leg's carrier has picked the goods up. Legs are picked up in order, each
+// once the previous one has been delivered.
+#[update]
+async fn confirm_pickup(shipment_id: String, leg_index: u32) -> Result<Shipment, String> {
+    let caller = msg_caller();
+    let carrier = require_active(caller, None).await?;
+
+    let mut shipment = load_shipment(&shipment_id)?;
+    let index = leg_index as usize;
+    let leg = shipment
+        .legs
+        .get(index)
+        .ok_or("Leg not found".to_string())?;
+
+    if leg.carrier != caller {
+        return Err("Only the leg's carrier can confirm pickup".to_string());
+    }
+    if leg.status != LegStatus::Planned {
+        return Err("Leg has already been picked up".to_string());
+    }
+    if !matches!(
+        shipment.status,
+        ShipmentStatus::Planned | ShipmentStatus::InTransit
+    ) {
+        return Err("Shipment is no longer active".to_string());
+    }
+    if index > 0 && shipment.legs[index - 1].status != LegStatus::Delivered {
+        return Err("The previous leg has not been delivered".to_string());
+    }
+
+    let current_time = time();
+    shipment.legs[index].status = LegStatus::PickedUp;
+    shipment.legs[index].picked_up_at = Some(current_time);
+    shipment.status = ShipmentStatus::InTransit;
+    shipment.updated_at = current_time;
+
+    SHIPMENTS.with(|s| s.borrow_mut().insert(shipment_id, shipment.clone()));
+    set_shipment_product_status(&shipment, ProductStatus::InTransit, None);
+
+    let leg = &shipment.legs[index];
+    record_shipment_event(
+        &shipment,
+        index,
+        carrier.role,
+        "SHIPMENT_PICKED_UP",
+        format!("Picked up for delivery to {}", leg.destination),
+        leg.origin.clone(),
+    );
+
+    Ok(shipment)
+}
+
+// Confirms a leg's delivery. Intermediate legs are confirmed by the next leg's carrier and
+// the final leg by the consignee, who then becomes the owner of the products.
+#[update]
+async fn confirm_delivery(shipment_id: String, leg_index: u32) -> Result<Shipment, String> {
+    let caller = msg_caller();
+    let account = require_active(caller, None).await?;
+
+    let mut shipment = load_shipment(&shipment_id)?;
+    let index = leg_index as usize;
+    let leg = shipment
+        .legs
+        .get(index)
+        .ok_or("Leg not found".to_string())?;
+    let is_final_leg = index + 1 == shipment.legs.len();
+
+    let receiver = match shipment.legs.get(index + 1) {
+        Some(next_leg) => next_leg.carrier,
+        None => shipment.consignee,
+    };
+    if receiver != caller {
+        return Err("Only the receiver of this leg can confirm delivery".to_string());
+    }
+    if leg.status != LegStatus::PickedUp {
+        return Err("Leg has not been picked up".to_string());
+    }
+
+    let current_time = time();
+    shipment.legs[index].status = LegStatus::Delivered;
+    shipment.legs[index].delivered_at = Some(current_time);
+    if is_final_leg {
+        shipment.status = ShipmentStatus::Delivered;
+    }
+    shipment.updated_at = current_time;
+
+    SHIPMENTS.with(|s| s.borrow_mut().insert(shipment_id, shipment.clone()));
+
+    let leg = &shipment.legs[index];
+    if is_final_leg {
+        set_shipment_product_status(
+            &shipment,
+            ProductStatus::Delivered,
+            Some(shipment.consignee),
+        );
+        record_shipment_event(
+            &shipment,
+            index,
+            account.role,
+            "SHIPMENT_DELIVERED",
+            "Shipment delivered to consignee".to_string(),
+            leg.destination.clone(),
+        );
+    } else {
+        record_shipment_event(
+            &shipment,
+            index,
+            account.role,
+            "SHIPMENT_LEG_DELIVERED",
+            format!("Handed over at {}", leg.destination),
+            leg.destination.clone(),
+        );
+    }
+
+    Ok(shipment)
+}
+
+// Shipments can only be cancelled by the shipper before the first pickup.
+#[update]
+async fn cancel_shipment(shipment_id: String) -> Result<Shipment, String> {
+    let caller = msg_caller();
+    let shipper = fetch_user(caller).await?;
+    let mut shipment = load_shipment(&shipment_id)?;
+
+    if shipment.shipper != caller {
+        return Err("Not authorized to cancel this shipment".to_string());
+    }
+    if shipment.status != ShipmentStatus::Planned {
+        return Err("Only shipments that have not been picked up can be cancelled".to_string());
+    }
+
+    shipment.status = ShipmentStatus::Cancelled;
+    shipment.updated_at = time();
+
+    SHIPMENTS.with(|s| s.borrow_mut().insert(shipment_id, shipment.clone()));
+    record_shipment_event(
+        &shipment,
+        0,
+        shipper.role,
+        "SHIPMENT_CANCELLED",
+        "Shipment cancelled by shipper".to_string(),
+        shipment.legs[0].origin.clone(),
+    );
+
+    Ok(shipment)
+}
+
+#[query]
+fn get_shipment(shipment_id: String) -> Result<Shipment, String> {
+    load_shipment(&shipment_id)
+}
+
+// Shipments the user sends, receives or carries a leg of.
+#[query]
+fn get_shipments_by_user(user: Principal) -> Vec<Shipment> {
+    SHIPMENTS.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, shipment)| {
+                shipment.shipper == user
+                    || shipment.consignee == user
+                    || shipment.legs.iter().any(|leg| leg.carrier == user)
+            })
+            .map(|(_, shipment)| shipment)
+            .collect()
+    })
+}
+
+#[query]
+fn get_statistics() -> (u64, u64, u64) {
+    let products_count = PRODUCTS.with(|p| p.borrow().len());
+    let tracking_events_count = TRACKING_EVENTS.with(|t| t.borrow().len());
+    let transfers_count = TRANSFERS.with(|t| t.borrow().len());
+
+    (products_count, tracking_events_count, transfers_count)
+}
+
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_tokens_lowercases_and_splits_on_punctuation() {
+        assert_eq!(
+            search_tokens("Organic-Coffee, ARABICA beans!"),
+            vec!["arabica", "beans", "coffee", "organic"]
+        );
+    }
+
+    #[test]
+    fn search_tokens_deduplicates() {
+        assert_eq!(search_tokens("Tea tea TEA green"), vec!["green", "tea"]);
+    }
+
+    #[test]
+    fn search_tokens_keeps_digits_and_non_ascii_letters() {
+        assert_eq!(
+            search_tokens("Café 2024 lot#7"),
+            vec!["2024", "7", "café", "lot"]
+        );
+    }
+
+    #[test]
+    fn search_tokens_of_blank_text_is_empty() {
+        assert!(search_tokens("").is_empty());
+        assert!(search_tokens("  --  ").is_empty());
+    }
+
+    // An order in `status` with one line per (ordered, shipped, received) triple.
+    fn order_with_lines(status: PurchaseOrderStatus, lines: &[(u32, u32, u32)]) -> PurchaseOrder {
+        PurchaseOrder {
+            id: "po-1".to_string(),
+            buyer: Principal::anonymous(),
+            supplier: Principal::anonymous(),
+            lines: lines
+                .iter()
+                .zip(1..)
+                .map(
+                    |(&(ordered, shipped, received), line_number)| PurchaseOrderLine {
+                        line_number,
+                        description: "Widgets".to_string(),
+                        category: "parts".to_string(),
+                        quantity_ordered: ordered,
+                        unit_price: 1.0,
+                        quantity_shipped: shipped,
+                        quantity_received: received,
+                        quantity_backordered: 0,
+                        backorder_expected_at: None,
+                    },
+                )
+                .collect(),
+            status,
+            notes: String::new(),
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    fn refreshed_status(
+        status: PurchaseOrderStatus,
+        lines: &[(u32, u32, u32)],
+    ) -> PurchaseOrderStatus {
+        let mut order = order_with_lines(status, lines);
+        refresh_purchase_order_status(&mut order);
+        order.status
+    }
+
+    #[test]
+    fn purchase_order_status_follows_shipments() {
+        use PurchaseOrderStatus::*;
+
+        assert_eq!(
+            refreshed_status(Accepted, &[(10, 0, 0), (5, 0, 0)]),
+            Accepted
+        );
+        assert_eq!(
+            refreshed_status(Accepted, &[(10, 4, 0), (5, 0, 0)]),
+            PartiallyFulfilled
+        );
+        assert_eq!(
+            refreshed_status(PartiallyFulfilled, &[(10, 10, 0), (5, 5, 0)]),
+            Fulfilled
+        );
+    }
+
+    #[test]
+    fn purchase_order_is_received_once_every_line_is_received() {
+        use PurchaseOrderStatus::*;
+
+        assert_eq!(
+            refreshed_status(Fulfilled, &[(10, 10, 10), (5, 5, 3)]),
+            Fulfilled
+        );
+        assert_eq!(
+            refreshed_status(Fulfilled, &[(10, 10, 10), (5, 5, 5)]),
+            Received
+        );
+    }
+
+    fn proof(quantity_received: u32, condition: DeliveryCondition) -> ProofOfDelivery {
+        ProofOfDelivery {
+            quantity_received,
+            condition,
+            evidence_hash: None,
+            location: "Dock 4".to_string(),
+        }
+    }
+
+    #[test]
+    fn proof_of_delivery_accepts_consistent_conditions() {
+        assert!(validate_proof_of_delivery(&proof(10, DeliveryCondition::Ok), 10).is_ok());
+        assert!(validate_proof_of_delivery(&proof(7, DeliveryCondition::Short), 10).is_ok());
+        assert!(validate_proof_of_delivery(&proof(10, DeliveryCondition::Damaged), 10).is_ok());
+        assert!(validate_proof_of_delivery(&proof(3, DeliveryCondition::Damaged), 10).is_ok());
+    }
+
+    #[test]
+    fn proof_of_delivery_rejects_inconsistent_quantities() {
+        assert!(validate_proof_of_delivery(&proof(11, DeliveryCondition::Damaged), 10).is_err());
+        assert!(validate_proof_of_delivery(&proof(9, DeliveryCondition::Ok), 10).is_err());
+        assert!(validate_proof_of_delivery(&proof(10, DeliveryCondition::Short), 10).is_err());
+    }
+
+    #[test]
+    fn proof_of_delivery_requires_location_and_valid_hash() {
+        let mut blank_location = proof(10, DeliveryCondition::Ok);
+        blank_location.location = "  ".to_string();
+        assert!(validate_proof_of_delivery(&blank_location, 10).is_err());
+
+        let mut blank_hash = proof(10, DeliveryCondition::Ok);
+        blank_hash.evidence_hash = Some(" ".to_string());
+        assert!(validate_proof_of_delivery(&blank_hash, 10).is_err());
+
+        let mut long_hash = proof(10, DeliveryCondition::Ok);
+        long_hash.evidence_hash = Some("a".repeat(MAX_EVIDENCE_HASH_LENGTH + 1));
+        assert!(validate_proof_of_delivery(&long_hash, 10).is_err());
+    }
+
+    #[test]
+    fn purchase_order_status_is_kept_outside_fulfilment() {
+        use PurchaseOrderStatus::*;
+
+        for status in [Submitted, Rejected, Cancelled, Received] {
+            assert_eq!(refreshed_status(status.clone(), &[(10, 10, 10)]), status);
+        }
+    }
+    fn product(id: &str, category: &str, price: f64) -> Product {
+        Product {
+            id: id.to_string(),
+            name: "Basmati rice".to_string(),
+            description: String::new(),
+            supplier_id: Principal::from_slice(&[1]),
+            current_owner: Principal::from_slice(&[1]),
+            status: ProductStatus::Created,
+            created_at: 0,
+            updated_at: 0,
+            batch_number: "B1".to_string(),
+            expiry_date: None,
+            price,
+            quantity: 1,
+            category: category.to_string(),
+            origin: "India".to_string(),
+            certifications: vec![],
+            owner_organization: None,
+        }
+    }
+
+    fn search(cursor: Option<String>, limit: u32) -> ProductSearchQuery {
+        ProductSearchQuery {
+            cursor,
+            limit,
+            ..Default::default()
+        }
+    }
+
+    fn ids(products: &[Product]) -> Vec<&str> {
+        products.iter().map(|product| product.id.as_str()).collect()
+    }
+
+    #[test]
+    fn product_pages_start_after_the_cursor() {
+        for id in ["p1", "p2", "p3", "p4", "p5"] {
+            save_product(&product(id, "Grain", 1.0));
+        }
+
+        let first = search_products(search(None, 2));
+        assert_eq!(ids(&first.products), vec!["p1", "p2"]);
+        assert_eq!(first.total, 5);
+
+        let second = search_products(search(first.next_cursor, 2));
+        assert_eq!(ids(&second.products), vec!["p3", "p4"]);
+        assert_eq!(second.total, 3);
+
+        let last = search_products(search(second.next_cursor, 2));
+        assert_eq!(ids(&last.products), vec!["p5"]);
+        assert_eq!(last.next_cursor, None);
+    }
+
+    #[test]
+    fn indexed_product_pages_start_after_the_cursor() {
+        for (id, category) in [
+            ("p1", "Grain"),
+            ("p2", "Spice"),
+            ("p3", "Grain"),
+            ("p4", "Grain"),
+        ] {
+            save_product(&product(id, category, 1.0));
+        }
+        let mut grain = search(None, 2);
+        grain.category = Some("grain".to_string());
+
+        let first = search_products(grain.clone());
+        assert_eq!(ids(&first.products), vec!["p1", "p3"]);
+        assert_eq!(first.next_cursor, Some("p3".to_string()));
+
+        grain.cursor = first.next_cursor;
+        let last = search_products(grain);
+        assert_eq!(ids(&last.products), vec!["p4"]);
+        assert_eq!(last.next_cursor, None);
+    }
+
+    #[test]
+    fn product_scan_stops_at_the_limit_and_resumes() {
+        for index in 0..=MAX_SEARCH_SCAN {
+            save_product(&product(&format!("p{:05}", index), "Grain", 1.0));
+        }
+        let expensive = format!("p{:05}", MAX_SEARCH_SCAN);
+        save_product(&product(&expensive, "Grain", 50.0));
+        let mut query = search(None, 10);
+        query.min_price = Some(10.0);
+
+        let first = search_products(query.clone());
+        assert!(first.products.is_empty());
+        assert_eq!(
+            first.next_cursor,
+            Some(format!("p{:05}", MAX_SEARCH_SCAN - 1))
+        );
+
+        query.cursor = first.next_cursor;
+        let second = search_products(query);
+        assert_eq!(ids(&second.products), vec![expensive.as_str()]);
+        assert_eq!(second.next_cursor, None);
+    }
+
+    #[test]
+    fn renewed_certification_gets_a_new_alert_id() {
+        let owner = Principal::from_slice(&[1]);
+
+        assert_ne!(
+            certification_alert_id(owner, "p1", "C-1", 100),
+            certification_alert_id(owner, "p1", "C-1", 200)
+        );
+    }
+
+    #[test]
+    fn legacy_alerts_are_moved_to_their_current_key() {
+        let owner = Principal::from_slice(&[1]);
+        let legacy_id = format!("{}|p1|C-1", owner.to_text());
+        let alert = CertificationAlert {
+            id: legacy_id.clone(),
+            owner,
+            product_id: "p1".to_string(),
+            scheme: "Organic".to_string(),
+            certificate_number: "C-1".to_string(),
+            valid_until: 100,
+            created_at: 1,
+        };
+        CERTIFICATION_ALERTS.with(|a| a.borrow_mut().insert(legacy_id.clone(), alert));
+
+        rekey_certification_alerts();
+
+        let current_id = certification_alert_id(owner, "p1", "C-1", 100);
+        CERTIFICATION_ALERTS.with(|a| {
+            let alerts = a.borrow();
+            assert!(!alerts.contains_key(&legacy_id));
+            assert_eq!(alerts.get(&current_id).unwrap().id, current_id);
+        });
+    }
+}
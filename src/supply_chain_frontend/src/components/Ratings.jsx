@@ -6,6 +6,9 @@ import AuthService from '../services/AuthService';
 const { Option } = Select;
 const { TextArea } = Input;
 
+// Mirrors RatingCategory in rating_backend. Which of these apply depends on the rated user's role.
+const RATING_CATEGORIES = ['Quality', 'Documentation', 'Punctuality', 'Handling', 'Payment', 'Communication', 'Overall'];
+
 const Ratings = () => {
     const [loading, setLoading] = useState(true);
     const [ratings, setRatings] = useState([]);
@@ -37,13 +40,10 @@ const Ratings = () => {
             setUsers(allUsers);
 
             // Load ratings by category
-            const qualityRatings = await ratingActor.get_ratings_by_category('quality');
-            const deliveryRatings = await ratingActor.get_ratings_by_category('delivery');
-            const communicationRatings = await ratingActor.get_ratings_by_category('communication');
-            const overallRatings = await ratingActor.get_ratings_by_category('overall');
-
-            const allRatings = [...qualityRatings, ...deliveryRatings, ...communicationRatings, ...overallRatings];
-            setRatings(allRatings);
+            const ratingsByCategory = await Promise.all(
+                RATING_CATEGORIES.map(category => ratingActor.get_ratings_by_category({ [category]: null }))
+            );
+            setRatings(ratingsByCategory.flat());
 
         } catch (error) {
             console.error('Failed to load ratings:', error);
@@ -62,7 +62,7 @@ const Ratings = () => {
                 values.transaction_id ? [values.transaction_id] : [],
                 values.rating,
                 values.review,
-                values.category
+                { [values.category]: null }
             );
             message.success('Rating submitted successfully');
             setModalVisible(false);
@@ -113,7 +113,7 @@ const Ratings = () => {
             dataIndex: 'category',
             key: 'category',
             render: (category) => (
-                <Tag color="blue">{category}</Tag>
+                <Tag color="blue">{Object.keys(category)[0]}</Tag>
             ),
         },
         {
@@ -301,10 +301,9 @@ const Ratings = () => {
                     </Form.Item>
                     <Form.Item name="category" label="Category" rules={[{ required: true }]}>
                         <Select placeholder="Select category">
-                            <Option value="quality">Quality</Option>
-                            <Option value="delivery">Delivery</Option>
-                            <Option value="communication">Communication</Option>
-                            <Option value="overall">Overall</Option>
+                            {RATING_CATEGORIES.map(category => (
+                                <Option key={category} value={category}>{category}</Option>
+                            ))}
                         </Select>
                     </Form.Item>
                     <Form.Item name="rating" label="Rating" rules={[{ required: true }]}>
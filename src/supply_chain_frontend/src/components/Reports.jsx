@@ -6,6 +6,46 @@ import AuthService from '../services/AuthService';
 const { Option } = Select;
 const { RangePicker } = DatePicker;
 
+const REPORT_TYPE_LABELS = {
+    SupplyChainOverview: 'Supply Chain Overview',
+    PerformanceReport: 'Performance Report',
+    TransactionSummary: 'Transaction Summary',
+    QualityMetrics: 'Quality Metrics',
+};
+
+// Candid variants arrive as single-key objects, e.g. { Count: 12n }.
+const variantKey = (variant) => (variant ? Object.keys(variant)[0] : undefined);
+
+const reportTypeLabel = (reportType) => REPORT_TYPE_LABELS[variantKey(reportType)] || 'Unknown';
+
+const formatReportValue = (value) => {
+    const kind = variantKey(value);
+    const inner = value?.[kind];
+    switch (kind) {
+        case 'Count':
+            return Number(inner).toLocaleString();
+        case 'Ratio':
+            return `${(inner * 100).toFixed(1)}%`;
+        case 'Money':
+            return (Number(inner.amount) / 100).toLocaleString(undefined, {
+                style: 'currency',
+                currency: inner.currency,
+            });
+        case 'Duration':
+            return `${(Number(inner) / 86_400_000_000_000).toFixed(1)} days`;
+        case 'Series':
+            return inner
+                .map(([timestamp, point]) => `${new Date(Number(timestamp) / 1000000).toLocaleDateString()}: ${point}`)
+                .join(', ');
+        case 'Table':
+            return inner.rows
+                .map(([label, cells]) => `${label} (${cells.map(formatReportValue).join(', ')})`)
+                .join('; ');
+        default:
+            return '-';
+    }
+};
+
 const Reports = () => {
     const [loading, setLoading] = useState(true);
     const [reports, setReports] = useState([]);
@@ -115,7 +155,7 @@ const Reports = () => {
 
             await reportingActor.generate_report(
                 values.title,
-                values.report_type,
+                { [values.report_type]: null },
                 periodStart,
                 periodEnd,
                 values.is_public || false
@@ -135,8 +175,8 @@ const Reports = () => {
         try {
             const reportData = {
                 title: report.title,
-                type: report.report_type,
-                data: report.data || [],
+                type: reportTypeLabel(report.report_type),
+                data: (report.data || []).map(([key, value]) => [key, formatReportValue(value)]),
                 summary: report.summary,
                 generated_at: new Date(Number(report.created_at) / 1000000).toISOString(),
             };
@@ -170,7 +210,7 @@ const Reports = () => {
             dataIndex: 'report_type',
             key: 'report_type',
             render: (type) => (
-                <Tag color="blue">{type || 'Unknown'}</Tag>
+                <Tag color="blue">{reportTypeLabel(type)}</Tag>
             ),
         },
         {
@@ -333,10 +373,9 @@ const Reports = () => {
                         rules={[{ required: true, message: 'Please select report type' }]}
                     >
                         <Select placeholder="Select report type">
-                            <Option value="SUPPLY_CHAIN_OVERVIEW">Supply Chain Overview</Option>
-                            <Option value="PERFORMANCE_REPORT">Performance Report</Option>
-                            <Option value="TRANSACTION_SUMMARY">Transaction Summary</Option>
-                            <Option value="QUALITY_METRICS">Quality Metrics</Option>
+                            {Object.entries(REPORT_TYPE_LABELS).map(([value, label]) => (
+                                <Option key={value} value={value}>{label}</Option>
+                            ))}
                         </Select>
                     </Form.Item>
 
@@ -375,7 +414,7 @@ const Reports = () => {
                 {selectedReport && (
                     <div>
                         <p><strong>Title:</strong> {selectedReport.title}</p>
-                        <p><strong>Type:</strong> {selectedReport.report_type}</p>
+                        <p><strong>Type:</strong> {reportTypeLabel(selectedReport.report_type)}</p>
                         <p><strong>Summary:</strong> {selectedReport.summary}</p>
                         <p><strong>Generated:</strong> {new Date(Number(selectedReport.created_at) / 1000000).toLocaleString()}</p>
 
@@ -384,7 +423,7 @@ const Reports = () => {
                                 <h4>Report Data:</h4>
                                 <div style={{ maxHeight: '300px', overflow: 'auto' }}>
                                     {selectedReport.data.map(([key, value], index) => (
-                                        <p key={index}><strong>{key}:</strong> {value}</p>
+                                        <p key={index}><strong>{key}:</strong> {formatReportValue(value)}</p>
                                     ))}
                                 </div>
                             </div>
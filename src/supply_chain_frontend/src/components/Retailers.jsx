@@ -161,7 +161,8 @@ const Retailers = () => {
                 selectedProduct.id,
                 statusEnum,
                 values.location || 'Retail Store',
-                values.notes || `Product sold by ${currentUser.name}`
+                values.notes || `Product sold by ${currentUser.name}`,
+                []
             );
 
             message.success('Product marked as sold successfully');
@@ -184,7 +185,8 @@ const Retailers = () => {
                 product.id,
                 statusEnum,
                 'Retail Store',
-                `Product received at retail store by ${currentUser.name}`
+                `Product received at retail store by ${currentUser.name}`,
+                []
             );
 
             message.success('Product received successfully');
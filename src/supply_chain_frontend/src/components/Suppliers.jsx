@@ -205,7 +205,16 @@ const Suppliers = () => {
                 parseInt(values.quantity) || 1,
                 values.category || 'General',
                 values.origin || 'Unknown',
-                values.certifications ? values.certifications.split(',').map(c => c.trim()) : []
+                values.certificationScheme ? [{
+                    scheme: values.certificationScheme,
+                    issuer: values.certificationIssuer || '',
+                    certificate_number: values.certificationNumber || '',
+                    valid_from: BigInt(Date.now()) * 1000000n,
+                    valid_until: BigInt(new Date(values.certificationValidUntil).getTime()) * 1000000n,
+                    document_hash: []
+                }] : [],
+                [],
+                []
             );
 
             message.success('Product created successfully');
@@ -232,7 +241,9 @@ const Suppliers = () => {
                 selectedProduct.id,
                 values.to_user,
                 values.transfer_type,
-                values.notes || `Product transferred by ${currentUser.role}`
+                values.notes || `Product transferred by ${currentUser.role}`,
+                [],
+                []
             );
 
             message.success('Product transferred successfully');
@@ -610,12 +621,50 @@ const Suppliers = () => {
                         <Input placeholder="Auto-generated if empty" />
                     </Form.Item>
 
-                    <Form.Item
-                        label="Certifications (comma-separated)"
-                        name="certifications"
-                    >
-                        <Input placeholder="ISO9001, FDA, etc." />
-                    </Form.Item>
+                    <Row gutter={16}>
+                        <Col span={6}>
+                            <Form.Item
+                                label="Certification"
+                                name="certificationScheme"
+                            >
+                                <Input placeholder="ISO9001, FDA, etc." />
+                            </Form.Item>
+                        </Col>
+                        <Col span={6}>
+                            <Form.Item
+                                label="Issuer"
+                                name="certificationIssuer"
+                            >
+                                <Input placeholder="Certifying body" />
+                            </Form.Item>
+                        </Col>
+                        <Col span={6}>
+                            <Form.Item
+                                label="Certificate Number"
+                                name="certificationNumber"
+                                dependencies={['certificationScheme']}
+                                rules={[({ getFieldValue }) => ({
+                                    required: !!getFieldValue('certificationScheme'),
+                                    message: 'Please enter the certificate number'
+                                })]}
+                            >
+                                <Input />
+                            </Form.Item>
+                        </Col>
+                        <Col span={6}>
+                            <Form.Item
+                                label="Valid Until"
+                                name="certificationValidUntil"
+                                dependencies={['certificationScheme']}
+                                rules={[({ getFieldValue }) => ({
+                                    required: !!getFieldValue('certificationScheme'),
+                                    message: 'Please enter the expiry date'
+                                })]}
+                            >
+                                <Input type="date" />
+                            </Form.Item>
+                        </Col>
+                    </Row>
 
                     <Form.Item>
                         <Space>
@@ -220,7 +220,8 @@ const Transporters = () => {
                 selectedProduct.id,
                 statusEnum,
                 values.location || 'In Transit',
-                values.notes || `Status updated to ${values.status} by Transporter`
+                values.notes || `Status updated to ${values.status} by Transporter`,
+                []
             );
 
             message.success('Product status updated successfully');
@@ -248,7 +249,9 @@ const Transporters = () => {
                 product.id,
                 currentUser.id,
                 'TO_TRANSPORTER',
-                'Product accepted for transport'
+                'Product accepted for transport',
+                [],
+                []
             );
 
             message.success('Product accepted for transport');
@@ -272,7 +275,9 @@ const Transporters = () => {
                 selectedProduct.id,
                 values.to_user,
                 values.transfer_type,
-                values.notes || 'Product transferred for delivery'
+                values.notes || 'Product transferred for delivery',
+                [],
+                []
             );
 
             message.success('Product transferred successfully');
@@ -194,7 +194,9 @@ const Warehouses = () => {
                 product.id,
                 currentUser.id,
                 'TO_WAREHOUSE',
-                'Product received at warehouse'
+                'Product received at warehouse',
+                [],
+                []
             );
 
             message.success('Product received successfully');
@@ -219,7 +221,8 @@ const Warehouses = () => {
                 selectedProduct.id,
                 statusEnum,
                 values.location || 'Warehouse',
-                values.notes || `Status updated to ${values.status} by Warehouse`
+                values.notes || `Status updated to ${values.status} by Warehouse`,
+                []
             );
 
             message.success('Product status updated successfully');
@@ -246,7 +249,9 @@ const Warehouses = () => {
                 selectedProduct.id,
                 values.to_user,
                 values.transfer_type,
-                values.notes || 'Product released from warehouse'
+                values.notes || 'Product released from warehouse',
+                [],
+                []
             );
 
             message.success('Product transferred successfully');
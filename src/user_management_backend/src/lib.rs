@@ -1,15 +1,38 @@
 use candid::{CandidType, Deserialize, Principal};
-use ic_cdk::api::time;
+use ic_cdk::api::{canister_self, is_controller, msg_caller, time};
+use ic_cdk::call::Call;
 use ic_cdk_macros::*;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
 use serde::Serialize;
 use std::borrow::Cow;
 use std::cell::RefCell;
+use std::ops::Bound;
+use std::time::Duration;
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
-#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Users are warned once a certification is within this window of lapsing.
+const CERTIFICATION_WARNING_WINDOW: u64 = 30 * NANOS_PER_DAY;
+const CERTIFICATION_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+const MAX_SEARCH_PAGE_SIZE: u32 = 50;
+const MAX_CERTIFICATIONS: usize = 20;
+const MAX_CERTIFICATION_FIELD_LENGTH: usize = 100;
+const MAX_ORGANIZATION_NAME_LENGTH: usize = 100;
+const MAX_DOCUMENT_HASH_LENGTH: usize = 128;
+const MAX_DOCUMENT_METADATA_ENTRIES: usize = 8;
+const MAX_DOCUMENT_METADATA_FIELD_LENGTH: usize = 64;
+const MAX_REJECTION_REASON_LENGTH: usize = 300;
+// Stable storage bound for one OnboardingDocument. The limits above keep ordinary documents
+// well within it; anything still larger is rejected rather than trapping on insert.
+const MAX_DOCUMENT_SIZE: u32 = 2048;
+// Upper bound on index entries read by one search call, so broad queries stay within the
+// instruction limit. Callers continue from the returned cursor.
+const MAX_SEARCH_SCAN: usize = 2000;
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
 pub enum UserRole {
     Supplier,
     Transporter,
@@ -18,6 +41,15 @@ pub enum UserRole {
     Admin,
 }
 
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Default)]
+pub enum AccountStatus {
+    #[default]
+    Active,
+    Suspended,   // by an admin
+    Deactivated, // by the user
+    Erased,      // personal data redacted; the principal is kept for supply chain history
+}
+
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
 pub struct User {
     pub id: Principal,
@@ -27,23 +59,212 @@ pub struct User {
     pub company_name: String,
     pub address: String,
     pub phone: String,
+    #[serde(default)]
+    pub share_region: bool, // whether the region of `address` can be searched by other users
     pub is_verified: bool,
+    #[serde(default)] // absent on users stored before account statuses existed
+    pub status: AccountStatus,
     pub created_at: u64,
     pub updated_at: u64,
     pub metadata: Vec<(String, String)>,
 }
 
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Certification {
+    pub scheme: String, // e.g. "ISO9001", "Organic"
+    pub issuer: String,
+    pub certificate_number: String,
+    pub valid_from: u64,
+    pub valid_until: u64,
+    pub document_hash: Option<String>,
+}
+
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
 pub struct UserProfile {
     pub user: User,
-    pub certifications: Vec<String>,
-    pub compliance_documents: Vec<String>,
+    #[serde(default)]
+    pub certifications: Vec<Certification>,
+    pub compliance_documents: Vec<String>, // ids of approved onboarding documents
     pub business_license: Option<String>,
     pub tax_id: Option<String>,
 }
 
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum DocumentType {
+    BusinessLicense,
+    TaxRegistration,
+    TransportLicense,
+    InsuranceCertificate,
+    WarehousePermit,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum DocumentStatus {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+// A compliance document submitted during onboarding. Only its hash is stored on-chain; the
+// file itself is reviewed off-chain against that hash.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct OnboardingDocument {
+    pub id: String,
+    pub user_id: Principal,
+    pub document_type: DocumentType,
+    pub document_hash: String,
+    pub metadata: Vec<(String, String)>,
+    pub submitted_at: u64,
+    pub status: DocumentStatus,
+    pub reviewed_by: Option<Principal>,
+    pub reviewed_at: Option<u64>,
+    pub rejection_reason: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct OnboardingStatus {
+    pub user_id: Principal,
+    pub role: UserRole,
+    pub is_verified: bool,
+    pub required_documents: Vec<DocumentType>,
+    pub missing_documents: Vec<DocumentType>, // required types with no approved document yet
+    pub documents: Vec<OnboardingDocument>,
+}
+
+// Raised for a user when one of their profile certifications is about to lapse.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct CertificationAlert {
+    pub id: String,
+    pub user_id: Principal,
+    pub scheme: String,
+    pub certificate_number: String,
+    pub valid_until: u64,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum OrgRole {
+    Owner,    // manages members and invitations
+    Operator, // acts on the organization's products
+    Viewer,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Organization {
+    pub id: String,
+    pub name: String,
+    pub created_by: Principal,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Membership {
+    pub organization_id: String,
+    pub member: Principal,
+    pub role: OrgRole,
+    pub joined_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum InvitationStatus {
+    Pending,
+    Accepted,
+    Declined,
+    Revoked,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Invitation {
+    pub id: String,
+    pub organization_id: String,
+    pub invitee: Principal,
+    pub role: OrgRole,
+    pub invited_by: Principal,
+    pub status: InvitationStatus,
+    pub created_at: u64,
+    pub responded_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum DelegationScope {
+    CreateProduct,
+    TransferProduct,
+    UpdateProductStatus,
+}
+
+// Who a delegate acts for: a single user, or an organization as a whole.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum DelegationSubject {
+    User(Principal),
+    Organization(String),
+}
+
+// Authorizes `delegate` (e.g. an ERP integration's principal) to make calls for `subject`
+// within `scopes` until `expires_at`.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Delegation {
+    pub id: String,
+    pub delegate: Principal,
+    pub subject: DelegationSubject,
+    pub scopes: Vec<DelegationScope>,
+    pub expires_at: u64,
+    pub granted_by: Principal,
+    pub created_at: u64,
+    pub revoked_at: Option<u64>,
+}
+
+// Principals of the other canisters this one calls, set by a controller after deployment.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct CanisterConfig {
+    pub supply_chain_canister: Option<Principal>,
+    pub rating_canister: Option<Principal>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct UserSearchQuery {
+    pub text: Option<String>, // matched as word prefixes against name and company
+    pub role: Option<UserRole>,
+    pub is_verified: Option<bool>,
+    pub region: Option<String>, // see region_of
+    pub min_reputation: Option<f64>,
+    pub cursor: Option<String>,
+    pub limit: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct UserSearchPage {
+    pub users: Vec<User>,
+    pub next_cursor: Option<String>, // None once the results are exhausted
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct InitArgs {
+    pub admin: Principal,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum AuditAction {
+    RoleChanged { from: UserRole, to: UserRole },
+    UserVerified,
+    DocumentReviewed { document_id: String, approved: bool },
+    UserSuspended { reason: String },
+    UserReactivated,
+    AccountDeactivated,
+    AccountErased,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct AuditEntry {
+    pub id: String,
+    pub actor: Principal,
+    pub subject: Principal,
+    pub action: AuditAction,
+    pub created_at: u64,
+}
+
 impl Storable for User {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(serde_json::to_vec(self).unwrap())
     }
 
@@ -58,8 +279,49 @@ impl Storable for User {
         };
 }
 
+// Profiles stored before certifications were structured list them by scheme name only. They
+// are kept as certifications of that scheme that never lapse, since no expiry was recorded.
+fn migrate_legacy_profile(bytes: &[u8]) -> UserProfile {
+    let mut profile: serde_json::Value = serde_json::from_slice(bytes).unwrap();
+
+    if let Some(certifications) = profile["certifications"].as_array() {
+        let certifications: Vec<serde_json::Value> = certifications
+            .iter()
+            .map(|certification| match certification.as_str() {
+                Some(scheme) => serde_json::json!(Certification {
+                    scheme: scheme.to_string(),
+                    issuer: String::new(),
+                    certificate_number: String::new(),
+                    valid_from: 0,
+                    valid_until: u64::MAX,
+                    document_hash: None,
+                }),
+                None => certification.clone(),
+            })
+            .collect();
+        profile["certifications"] = serde_json::Value::Array(certifications);
+    }
+
+    serde_json::from_value(profile).unwrap()
+}
+
 impl Storable for UserProfile {
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap_or_else(|_| migrate_legacy_profile(&bytes))
+    }
+
+    // Embeds the user and up to MAX_CERTIFICATIONS certifications, whose size after JSON
+    // escaping has no useful fixed limit.
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Storable for CertificationAlert {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(serde_json::to_vec(self).unwrap())
     }
 
@@ -69,7 +331,119 @@ impl Storable for UserProfile {
 
     const BOUND: ic_stable_structures::storable::Bound =
         ic_stable_structures::storable::Bound::Bounded {
-            max_size: 2048,
+            max_size: 1024,
+            is_fixed_size: false,
+        };
+}
+
+impl Storable for OnboardingDocument {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Bounded {
+            max_size: MAX_DOCUMENT_SIZE,
+            is_fixed_size: false,
+        };
+}
+
+impl Storable for Organization {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Bounded {
+            max_size: 1024,
+            is_fixed_size: false,
+        };
+}
+
+impl Storable for Membership {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Bounded {
+            max_size: 512,
+            is_fixed_size: false,
+        };
+}
+
+impl Storable for Invitation {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Bounded {
+            max_size: 1024,
+            is_fixed_size: false,
+        };
+}
+
+impl Storable for Delegation {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Bounded {
+            max_size: 1024,
+            is_fixed_size: false,
+        };
+}
+
+impl Storable for CanisterConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Bounded {
+            max_size: 512,
+            is_fixed_size: false,
+        };
+}
+
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(serde_json::to_vec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Bounded {
+            max_size: 1024,
             is_fixed_size: false,
         };
 }
@@ -90,192 +464,2013 @@ thread_local! {
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
         )
     );
+
+    static AUDIT_LOG: RefCell<StableBTreeMap<String, AuditEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
+        )
+    );
+
+    static DOCUMENTS: RefCell<StableBTreeMap<String, OnboardingDocument, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+        )
+    );
+
+    // Keyed by certification_alert_id, so each certificate is warned about once per expiry.
+    static CERTIFICATION_ALERTS: RefCell<StableBTreeMap<String, CertificationAlert, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
+        )
+    );
+
+    static ORGANIZATIONS: RefCell<StableBTreeMap<String, Organization, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+        )
+    );
+
+    // Keyed by "{organization_id}|{member}".
+    static MEMBERSHIPS: RefCell<StableBTreeMap<String, Membership, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+        )
+    );
+
+    static INVITATIONS: RefCell<StableBTreeMap<String, Invitation, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+        )
+    );
+
+    static DELEGATIONS: RefCell<StableBTreeMap<String, Delegation, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+        )
+    );
+
+    static CANISTER_CONFIG: RefCell<StableCell<CanisterConfig, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))),
+            CanisterConfig::default(),
+        )
+        .expect("Failed to initialize canister config")
+    );
+
+    // Directory search index over active users. Keys are "name:{word}|{user}" for each word
+    // of the name and company, plus "role:{role}|{user}" and "region:{region}|{user}".
+    static USER_SEARCH_INDEX: RefCell<StableBTreeMap<String, Principal, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+        )
+    );
 }
 
-#[update]
-fn register_user(
-    name: String,
-    email: String,
-    role: UserRole,
-    company_name: String,
-    address: String,
-    phone: String,
-) -> Result<User, String> {
-    let caller = ic_cdk::caller();
+// The first admin is created from the init args, or from the upgrade args when upgrading from
+// a release without roles; every later admin is granted by an existing one through
+// set_user_role.
+#[init]
+fn init(args: InitArgs) {
+    install_admin(args.admin);
+    start_timers();
+}
+
+// Makes `admin` a verified admin, registering them if they are not a user yet.
+fn install_admin(admin: Principal) {
     let current_time = time();
 
-    // Check if user already exists
-    if USERS.with(|u| u.borrow().contains_key(&caller)) {
-        return Err("User already registered".to_string());
+    if let Some(mut user) = USERS.with(|u| u.borrow().get(&admin)) {
+        if user.role != UserRole::Admin {
+            let previous_role = std::mem::replace(&mut user.role, UserRole::Admin);
+            user.updated_at = current_time;
+            save_user(&user);
+            record_audit(
+                canister_self(),
+                admin,
+                AuditAction::RoleChanged {
+                    from: previous_role,
+                    to: UserRole::Admin,
+                },
+            );
+        }
+        refresh_verification(&mut user, canister_self());
+        return;
     }
 
-    let user = User {
-        id: caller,
-        name,
-        email,
-        role,
-        company_name,
-        address,
-        phone,
-        is_verified: true, // Auto-verify all users in demo mode
+    let admin = User {
+        id: admin,
+        name: "Administrator".to_string(),
+        email: String::new(),
+        role: UserRole::Admin,
+        company_name: String::new(),
+        address: String::new(),
+        phone: String::new(),
+        share_region: false,
+        is_verified: true,
+        status: AccountStatus::Active,
         created_at: current_time,
         updated_at: current_time,
         metadata: vec![],
     };
 
-    USERS.with(|u| u.borrow_mut().insert(caller, user.clone()));
+    store_user(&admin);
+    USER_PROFILES.with(|p| {
+        p.borrow_mut().insert(
+            admin.id,
+            UserProfile {
+                user: admin,
+                certifications: vec![],
+                compliance_documents: vec![],
+                business_license: None,
+                tax_id: None,
+            },
+        )
+    });
+}
 
-    // Create user profile
-    let profile = UserProfile {
-        user: user.clone(),
-        certifications: vec![],
-        compliance_documents: vec![],
-        business_license: None,
-        tax_id: None,
-    };
+#[post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    // Releases before roles were enforced let users register as Admin and verified everyone
+    // on registration. Every role change and verification since is audited, so while the
+    // audit log is empty the first upgrade that names an admin revokes both.
+    if let Some(args) = args {
+        if AUDIT_LOG.with(|a| a.borrow().is_empty()) {
+            reset_legacy_roles(args.admin);
+        }
+    }
 
-    USER_PROFILES.with(|p| p.borrow_mut().insert(caller, profile));
+    // Users registered before the search index existed are indexed once.
+    if USER_SEARCH_INDEX.with(|i| i.borrow().is_empty()) {
+        let users: Vec<User> = USERS.with(|u| u.borrow().iter().map(|(_, user)| user).collect());
+        for user in users {
+            update_search_index(None, &user);
+        }
+    }
+    remove_unshared_regions();
+    rekey_certification_alerts();
 
-    Ok(user)
+    start_timers();
 }
 
-#[update]
-fn update_user_profile(
-    name: Option<String>,
-    email: Option<String>,
-    company_name: Option<String>,
-    address: Option<String>,
-    phone: Option<String>,
-) -> Result<User, String> {
-    let caller = ic_cdk::caller();
-    let current_time = time();
-
-    let mut user = USERS.with(|u| u.borrow().get(&caller).ok_or("User not found".to_string()))?;
+// Demotes every admin other than `admin` and re-checks every user's verification against
+// their approved documents. Retailer grants no privileges; an admin can assign the right role
+// with set_user_role.
+fn reset_legacy_roles(admin: Principal) {
+    let users: Vec<User> = USERS.with(|u| u.borrow().iter().map(|(_, user)| user).collect());
+    for mut user in users {
+        if user.id == admin {
+            continue;
+        }
 
-    if let Some(name) = name {
-        user.name = name;
+        if user.role == UserRole::Admin {
+            user.role = UserRole::Retailer;
+            user.updated_at = time();
+            save_user(&user);
+            record_audit(
+                canister_self(),
+                user.id,
+                AuditAction::RoleChanged {
+                    from: UserRole::Admin,
+                    to: UserRole::Retailer,
+                },
+            );
+        }
+        refresh_verification(&mut user, canister_self());
     }
-    if let Some(email) = email {
+
+    install_admin(admin);
+}
+
+// Earlier releases indexed every user's region. Drops the entries of users who have not
+// chosen to share it.
+fn remove_unshared_regions() {
+    let entries: Vec<(String, Principal)> = USER_SEARCH_INDEX.with(|i| {
+        i.borrow()
+            .range("region:".to_string()..)
+            .take_while(|(key, _)| key.starts_with("region:"))
+            .collect()
+    });
+
+    for (key, user_id) in entries {
+        let shared = USERS
+            .with(|u| u.borrow().get(&user_id))
+            .is_some_and(|user| user.share_region);
+        if !shared {
+            USER_SEARCH_INDEX.with(|i| i.borrow_mut().remove(&key));
+        }
+    }
+}
+
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(CERTIFICATION_CHECK_INTERVAL, warn_expiring_certifications);
+}
+
+fn validate_certification(certification: &Certification, now: u64) -> Result<(), String> {
+    if certification.scheme.is_empty() || certification.certificate_number.is_empty() {
+        return Err("Certification scheme and number are required".to_string());
+    }
+
+    let fields = [
+        &certification.scheme,
+        &certification.issuer,
+        &certification.certificate_number,
+    ];
+    if fields
+        .into_iter()
+        .chain(&certification.document_hash)
+        .any(|field| field.chars().count() > MAX_CERTIFICATION_FIELD_LENGTH)
+    {
+        return Err(format!(
+            "Certification fields must be at most {} characters",
+            MAX_CERTIFICATION_FIELD_LENGTH
+        ));
+    }
+
+    if certification.valid_from > certification.valid_until {
+        return Err(format!(
+            "Certification {} is valid until before it is valid from",
+            certification.certificate_number
+        ));
+    }
+
+    if certification.valid_until <= now {
+        return Err(format!(
+            "Certification {} has expired",
+            certification.certificate_number
+        ));
+    }
+
+    Ok(())
+}
+
+// Includes the expiry so a certificate renewed under the same number is warned about again.
+fn certification_alert_id(user: Principal, certificate_number: &str, valid_until: u64) -> String {
+    format!(
+        "{}|{}|{:020}",
+        user.to_text(),
+        certificate_number,
+        valid_until
+    )
+}
+
+// Alerts raised before the expiry was part of the key are moved to their current key, so
+// they are not raised a second time.
+fn rekey_certification_alerts() {
+    let stale: Vec<(String, CertificationAlert)> = CERTIFICATION_ALERTS.with(|a| {
+        a.borrow()
+            .iter()
+            .filter(|(key, alert)| {
+                *key != certification_alert_id(
+                    alert.user_id,
+                    &alert.certificate_number,
+                    alert.valid_until,
+                )
+            })
+            .collect()
+    });
+
+    for (key, mut alert) in stale {
+        alert.id =
+            certification_alert_id(alert.user_id, &alert.certificate_number, alert.valid_until);
+        CERTIFICATION_ALERTS.with(|a| {
+            let mut alerts = a.borrow_mut();
+            alerts.remove(&key);
+            alerts.insert(alert.id.clone(), alert);
+        });
+    }
+}
+
+fn warn_expiring_certifications() {
+    let now = time();
+    let profiles: Vec<UserProfile> =
+        USER_PROFILES.with(|p| p.borrow().iter().map(|(_, profile)| profile).collect());
+
+    for profile in profiles {
+        for certification in &profile.certifications {
+            if certification.valid_until > now + CERTIFICATION_WARNING_WINDOW {
+                continue;
+            }
+
+            let alert_id = certification_alert_id(
+                profile.user.id,
+                &certification.certificate_number,
+                certification.valid_until,
+            );
+            if CERTIFICATION_ALERTS.with(|a| a.borrow().contains_key(&alert_id)) {
+                continue;
+            }
+
+            let alert = CertificationAlert {
+                id: alert_id.clone(),
+                user_id: profile.user.id,
+                scheme: certification.scheme.clone(),
+                certificate_number: certification.certificate_number.clone(),
+                valid_until: certification.valid_until,
+                created_at: now,
+            };
+
+            CERTIFICATION_ALERTS.with(|a| a.borrow_mut().insert(alert_id, alert));
+        }
+    }
+}
+
+fn is_admin(principal: &Principal) -> bool {
+    USERS.with(|u| {
+        u.borrow().get(principal).is_some_and(|user| {
+            user.role == UserRole::Admin && user.status == AccountStatus::Active
+        })
+    })
+}
+
+// True when `user` is the only active admin left, who must not be demoted or disabled.
+fn is_last_admin(user: &User) -> bool {
+    if !(user.role == UserRole::Admin && user.status == AccountStatus::Active) {
+        return false;
+    }
+
+    let admin_count = USERS.with(|u| {
+        u.borrow()
+            .iter()
+            .filter(|(_, user)| {
+                user.role == UserRole::Admin && user.status == AccountStatus::Active
+            })
+            .count()
+    });
+    admin_count <= 1
+}
+
+fn require_active(principal: &Principal) -> Result<User, String> {
+    let user = USERS.with(|u| {
+        u.borrow()
+            .get(principal)
+            .ok_or("User not found".to_string())
+    })?;
+
+    if user.status != AccountStatus::Active {
+        return Err("Account is not active".to_string());
+    }
+
+    Ok(user)
+}
+
+fn require_admin(principal: &Principal) -> Result<(), String> {
+    if is_admin(principal) {
+        Ok(())
+    } else {
+        Err("Only admins can perform this action".to_string())
+    }
+}
+
+fn record_audit(actor: Principal, subject: Principal, action: AuditAction) {
+    // Entries are never removed, so the log's length is a sequence number that keeps ids
+    // unique when one call records several entries.
+    let sequence = AUDIT_LOG.with(|a| a.borrow().len());
+    let entry = AuditEntry {
+        id: format!("{}-{:010}", time(), sequence),
+        actor,
+        subject,
+        action,
+        created_at: time(),
+    };
+
+    AUDIT_LOG.with(|a| a.borrow_mut().insert(entry.id.clone(), entry));
+}
+
+// Documents a user must have approved before they are verified.
+fn required_documents(role: &UserRole) -> Vec<DocumentType> {
+    match role {
+        UserRole::Supplier => vec![DocumentType::BusinessLicense, DocumentType::TaxRegistration],
+        UserRole::Transporter => vec![
+            DocumentType::BusinessLicense,
+            DocumentType::TransportLicense,
+            DocumentType::InsuranceCertificate,
+        ],
+        UserRole::Warehouse => vec![
+            DocumentType::BusinessLicense,
+            DocumentType::WarehousePermit,
+            DocumentType::InsuranceCertificate,
+        ],
+        UserRole::Retailer => vec![DocumentType::BusinessLicense, DocumentType::TaxRegistration],
+        UserRole::Admin => vec![],
+    }
+}
+
+fn documents_for(user_id: Principal) -> Vec<OnboardingDocument> {
+    DOCUMENTS.with(|d| {
+        d.borrow()
+            .iter()
+            .filter(|(_, document)| document.user_id == user_id)
+            .map(|(_, document)| document)
+            .collect()
+    })
+}
+
+fn missing_documents(user: &User) -> Vec<DocumentType> {
+    let documents = documents_for(user.id);
+    required_documents(&user.role)
+        .into_iter()
+        .filter(|required| {
+            !documents.iter().any(|document| {
+                document.document_type == *required && document.status == DocumentStatus::Approved
+            })
+        })
+        .collect()
+}
+
+// Recomputes is_verified from the user's approved documents, saving and auditing any change.
+fn refresh_verification(user: &mut User, actor: Principal) {
+    let is_verified = missing_documents(user).is_empty();
+    if user.is_verified == is_verified {
+        return;
+    }
+
+    user.is_verified = is_verified;
+    user.updated_at = time();
+    save_user(user);
+
+    if is_verified {
+        record_audit(actor, user.id, AuditAction::UserVerified);
+    }
+}
+
+// Lowercased alphanumeric words of `text`, sorted and deduplicated.
+fn search_tokens(text: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(|token| token.to_lowercase())
+        .collect();
+    tokens.sort();
+    tokens.dedup();
+    tokens
+}
+
+// Addresses are free text; the region is taken to be their last comma-separated part, e.g.
+// "maharashtra" in "12 Dock Road, Mumbai, Maharashtra".
+fn region_of(address: &str) -> Option<String> {
+    address
+        .rsplit(',')
+        .next()
+        .map(|region| region.trim().to_lowercase())
+        .filter(|region| !region.is_empty())
+}
+
+// The address itself is private, so a user's region is only indexed and matched once they
+// choose to share it.
+fn shared_region(user: &User) -> Option<String> {
+    if user.share_region {
+        region_of(&user.address)
+    } else {
+        None
+    }
+}
+
+fn search_words(user: &User) -> Vec<String> {
+    search_tokens(&format!("{} {}", user.name, user.company_name))
+}
+
+fn search_keys(user: &User) -> Vec<String> {
+    if user.status != AccountStatus::Active {
+        return vec![];
+    }
+
+    let id = user.id.to_text();
+    let mut keys: Vec<String> = search_words(user)
+        .into_iter()
+        .map(|word| format!("name:{}|{}", word, id))
+        .collect();
+    keys.push(format!("role:{:?}|{}", user.role, id));
+    if let Some(region) = shared_region(user) {
+        keys.push(format!("region:{}|{}", region, id));
+    }
+    keys
+}
+
+fn update_search_index(previous: Option<&User>, user: &User) {
+    USER_SEARCH_INDEX.with(|i| {
+        let mut index = i.borrow_mut();
+        for key in previous.map(search_keys).unwrap_or_default() {
+            index.remove(&key);
+        }
+        for key in search_keys(user) {
+            index.insert(key, user.id);
+        }
+    });
+}
+
+// Writes a user record, keeping the search index in step.
+fn store_user(user: &User) {
+    let previous = USERS.with(|u| u.borrow_mut().insert(user.id, user.clone()));
+    update_search_index(previous.as_ref(), user);
+}
+
+// Saves a user and keeps the copy embedded in their profile in sync.
+fn save_user(user: &User) {
+    store_user(user);
+    USER_PROFILES.with(|p| {
+        let mut profiles = p.borrow_mut();
+        if let Some(mut profile) = profiles.get(&user.id) {
+            profile.user = user.clone();
+            profiles.insert(user.id, profile);
+        }
+    });
+}
+
+#[update]
+fn register_user(
+    name: String,
+    email: String,
+    role: UserRole,
+    company_name: String,
+    address: String,
+    phone: String,
+) -> Result<User, String> {
+    let caller = msg_caller();
+    let current_time = time();
+
+    // Check if user already exists
+    if USERS.with(|u| u.borrow().contains_key(&caller)) {
+        return Err("User already registered".to_string());
+    }
+
+    if role == UserRole::Admin {
+        return Err("The Admin role can only be granted by an admin".to_string());
+    }
+
+    let user = User {
+        id: caller,
+        name,
+        email,
+        role,
+        company_name,
+        address,
+        phone,
+        share_region: false,
+        is_verified: false,
+        status: AccountStatus::Active,
+        created_at: current_time,
+        updated_at: current_time,
+        metadata: vec![],
+    };
+
+    store_user(&user);
+
+    // Create user profile
+    let profile = UserProfile {
+        user: user.clone(),
+        certifications: vec![],
+        compliance_documents: vec![],
+        business_license: None,
+        tax_id: None,
+    };
+
+    USER_PROFILES.with(|p| p.borrow_mut().insert(caller, profile));
+
+    Ok(user)
+}
+
+#[update]
+fn update_user_profile(
+    name: Option<String>,
+    email: Option<String>,
+    company_name: Option<String>,
+    address: Option<String>,
+    phone: Option<String>,
+    share_region: Option<bool>,
+) -> Result<User, String> {
+    let caller = msg_caller();
+    let current_time = time();
+
+    let mut user = require_active(&caller)?;
+
+    if let Some(name) = name {
+        user.name = name;
+    }
+    if let Some(email) = email {
         user.email = email;
     }
-    if let Some(company_name) = company_name {
-        user.company_name = company_name;
+    if let Some(company_name) = company_name {
+        user.company_name = company_name;
+    }
+    if let Some(address) = address {
+        user.address = address;
+    }
+    if let Some(phone) = phone {
+        user.phone = phone;
+    }
+    if let Some(share_region) = share_region {
+        user.share_region = share_region;
+    }
+
+    user.updated_at = current_time;
+
+    store_user(&user);
+
+    // Update profile
+    let mut profile = USER_PROFILES.with(|p| {
+        p.borrow()
+            .get(&caller)
+            .ok_or("Profile not found".to_string())
+    })?;
+
+    profile.user = user.clone();
+    USER_PROFILES.with(|p| p.borrow_mut().insert(caller, profile));
+
+    Ok(user)
+}
+
+// Re-evaluates a user's verification. Users are only verified once every document their role
+// requires has been approved.
+#[update]
+fn verify_user(user_id: Principal) -> Result<User, String> {
+    let caller = msg_caller();
+    require_admin(&caller)?;
+
+    let mut user = USERS.with(|u| u.borrow().get(&user_id).ok_or("User not found".to_string()))?;
+
+    let missing = missing_documents(&user);
+    if !missing.is_empty() {
+        return Err(format!("User is missing approved documents: {:?}", missing));
+    }
+
+    refresh_verification(&mut user, caller);
+
+    Ok(user)
+}
+
+#[update]
+fn set_user_role(user_id: Principal, role: UserRole) -> Result<User, String> {
+    let caller = msg_caller();
+    require_admin(&caller)?;
+
+    let mut user = USERS.with(|u| u.borrow().get(&user_id).ok_or("User not found".to_string()))?;
+
+    if user.role == role {
+        return Ok(user);
+    }
+
+    if is_last_admin(&user) {
+        return Err("Cannot remove the last admin".to_string());
+    }
+
+    let previous_role = std::mem::replace(&mut user.role, role.clone());
+    user.updated_at = time();
+
+    save_user(&user);
+    record_audit(
+        caller,
+        user_id,
+        AuditAction::RoleChanged {
+            from: previous_role,
+            to: role,
+        },
+    );
+
+    // The new role may require different documents.
+    refresh_verification(&mut user, caller);
+
+    Ok(user)
+}
+
+#[update]
+fn suspend_user(user_id: Principal, reason: String) -> Result<User, String> {
+    let caller = msg_caller();
+    require_admin(&caller)?;
+
+    if user_id == caller {
+        return Err("Admins cannot suspend themselves".to_string());
+    }
+
+    let mut user = USERS.with(|u| u.borrow().get(&user_id).ok_or("User not found".to_string()))?;
+
+    if user.status != AccountStatus::Active {
+        return Err("Only active accounts can be suspended".to_string());
+    }
+
+    user.status = AccountStatus::Suspended;
+    user.updated_at = time();
+
+    save_user(&user);
+    record_audit(caller, user_id, AuditAction::UserSuspended { reason });
+
+    Ok(user)
+}
+
+// Restores a suspended or deactivated account. Erased accounts cannot be restored.
+#[update]
+fn reactivate_user(user_id: Principal) -> Result<User, String> {
+    let caller = msg_caller();
+    require_admin(&caller)?;
+
+    let mut user = USERS.with(|u| u.borrow().get(&user_id).ok_or("User not found".to_string()))?;
+
+    match user.status {
+        AccountStatus::Suspended | AccountStatus::Deactivated => {}
+        AccountStatus::Active => return Err("Account is already active".to_string()),
+        AccountStatus::Erased => return Err("Erased accounts cannot be reactivated".to_string()),
+    }
+
+    user.status = AccountStatus::Active;
+    user.updated_at = time();
+
+    save_user(&user);
+    record_audit(caller, user_id, AuditAction::UserReactivated);
+
+    Ok(user)
+}
+
+#[update]
+fn deactivate_account() -> Result<User, String> {
+    let caller = msg_caller();
+    let mut user = require_active(&caller)?;
+
+    if is_last_admin(&user) {
+        return Err("Cannot deactivate the last admin".to_string());
+    }
+
+    user.status = AccountStatus::Deactivated;
+    user.updated_at = time();
+
+    save_user(&user);
+    record_audit(caller, caller, AuditAction::AccountDeactivated);
+
+    Ok(user)
+}
+
+// Redacts a user's personal data, either at their own request or by an admin. The record and
+// principal are kept so products, transfers and ratings that reference them stay intact.
+#[update]
+fn erase_user_data(user_id: Principal) -> Result<User, String> {
+    let caller = msg_caller();
+    if caller != user_id {
+        require_admin(&caller)?;
+    }
+
+    let mut user = USERS.with(|u| u.borrow().get(&user_id).ok_or("User not found".to_string()))?;
+
+    if user.status == AccountStatus::Erased {
+        return Err("Account has already been erased".to_string());
+    }
+
+    if is_last_admin(&user) {
+        return Err("Cannot erase the last admin".to_string());
+    }
+
+    user.name = String::new();
+    user.company_name = String::new();
+    user.email = String::new();
+    user.phone = String::new();
+    user.address = String::new();
+    user.metadata = vec![];
+    user.status = AccountStatus::Erased;
+    user.updated_at = time();
+
+    USER_PROFILES.with(|p| {
+        let mut profiles = p.borrow_mut();
+        if let Some(mut profile) = profiles.get(&user_id) {
+            profile.business_license = None;
+            profile.tax_id = None;
+            profiles.insert(user_id, profile);
+        }
+    });
+
+    // Document metadata can hold personal details; only the hashes are kept.
+    for mut document in documents_for(user_id) {
+        document.metadata = vec![];
+        DOCUMENTS.with(|d| d.borrow_mut().insert(document.id.clone(), document));
+    }
+
+    save_user(&user);
+    record_audit(caller, user_id, AuditAction::AccountErased);
+
+    Ok(user)
+}
+
+#[query]
+fn get_audit_log() -> Result<Vec<AuditEntry>, String> {
+    require_admin(&msg_caller())?;
+
+    Ok(AUDIT_LOG.with(|a| a.borrow().iter().map(|(_, entry)| entry).collect()))
+}
+
+#[update]
+fn add_certification(certification: Certification) -> Result<UserProfile, String> {
+    let caller = msg_caller();
+    require_active(&caller)?;
+
+    validate_certification(&certification, time())?;
+
+    let mut profile = USER_PROFILES.with(|p| {
+        p.borrow()
+            .get(&caller)
+            .ok_or("Profile not found".to_string())
+    })?;
+
+    if profile.certifications.len() >= MAX_CERTIFICATIONS {
+        return Err(format!(
+            "A profile can have at most {} certifications",
+            MAX_CERTIFICATIONS
+        ));
+    }
+
+    profile.certifications.push(certification);
+
+    USER_PROFILES.with(|p| p.borrow_mut().insert(caller, profile.clone()));
+
+    Ok(profile)
+}
+
+#[update]
+fn submit_document(
+    document_type: DocumentType,
+    document_hash: String,
+    metadata: Vec<(String, String)>,
+) -> Result<OnboardingDocument, String> {
+    let caller = msg_caller();
+    require_active(&caller)?;
+
+    validate_document(&document_hash, &metadata)?;
+
+    // Documents are never removed, so the count is a sequence number that keeps ids unique
+    // within a round.
+    let sequence = DOCUMENTS.with(|d| d.borrow().len());
+    let document = OnboardingDocument {
+        id: format!("{}-{:010}", time(), sequence),
+        user_id: caller,
+        document_type,
+        document_hash,
+        metadata,
+        submitted_at: time(),
+        status: DocumentStatus::Pending,
+        reviewed_by: None,
+        reviewed_at: None,
+        rejection_reason: None,
+    };
+    check_document_size(&document)?;
+
+    DOCUMENTS.with(|d| d.borrow_mut().insert(document.id.clone(), document.clone()));
+
+    Ok(document)
+}
+
+fn validate_document(document_hash: &str, metadata: &[(String, String)]) -> Result<(), String> {
+    if document_hash.is_empty() {
+        return Err("Document hash is required".to_string());
+    }
+    if document_hash.chars().count() > MAX_DOCUMENT_HASH_LENGTH {
+        return Err(format!(
+            "Document hash must be at most {} characters",
+            MAX_DOCUMENT_HASH_LENGTH
+        ));
+    }
+
+    if metadata.len() > MAX_DOCUMENT_METADATA_ENTRIES {
+        return Err(format!(
+            "A document can have at most {} metadata entries",
+            MAX_DOCUMENT_METADATA_ENTRIES
+        ));
+    }
+    if metadata.iter().any(|(key, value)| {
+        key.chars().count() > MAX_DOCUMENT_METADATA_FIELD_LENGTH
+            || value.chars().count() > MAX_DOCUMENT_METADATA_FIELD_LENGTH
+    }) {
+        return Err(format!(
+            "Document metadata keys and values must be at most {} characters",
+            MAX_DOCUMENT_METADATA_FIELD_LENGTH
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_document_size(document: &OnboardingDocument) -> Result<(), String> {
+    if document.to_bytes().len() > MAX_DOCUMENT_SIZE as usize {
+        return Err("Document is too large to store".to_string());
+    }
+    Ok(())
+}
+
+// Moves a pending document to Approved or Rejected.
+fn apply_review(
+    mut document: OnboardingDocument,
+    approved: bool,
+    rejection_reason: Option<String>,
+    reviewer: Principal,
+    now: u64,
+) -> Result<OnboardingDocument, String> {
+    if document.status != DocumentStatus::Pending {
+        return Err("Document has already been reviewed".to_string());
+    }
+
+    if approved {
+        document.status = DocumentStatus::Approved;
+        document.rejection_reason = None;
+    } else {
+        let reason =
+            rejection_reason.ok_or("A reason is required when rejecting a document".to_string())?;
+        if reason.chars().count() > MAX_REJECTION_REASON_LENGTH {
+            return Err(format!(
+                "Rejection reason must be at most {} characters",
+                MAX_REJECTION_REASON_LENGTH
+            ));
+        }
+        document.status = DocumentStatus::Rejected;
+        document.rejection_reason = Some(reason);
+    }
+    document.reviewed_by = Some(reviewer);
+    document.reviewed_at = Some(now);
+    check_document_size(&document)?;
+
+    Ok(document)
+}
+
+#[update]
+fn review_document(
+    document_id: String,
+    approved: bool,
+    rejection_reason: Option<String>,
+) -> Result<OnboardingDocument, String> {
+    let caller = msg_caller();
+    require_admin(&caller)?;
+
+    let document = DOCUMENTS.with(|d| {
+        d.borrow()
+            .get(&document_id)
+            .ok_or("Document not found".to_string())
+    })?;
+    let document = apply_review(document, approved, rejection_reason, caller, time())?;
+
+    DOCUMENTS.with(|d| d.borrow_mut().insert(document_id.clone(), document.clone()));
+    record_audit(
+        caller,
+        document.user_id,
+        AuditAction::DocumentReviewed {
+            document_id: document_id.clone(),
+            approved,
+        },
+    );
+
+    if approved {
+        USER_PROFILES.with(|p| {
+            let mut profiles = p.borrow_mut();
+            if let Some(mut profile) = profiles.get(&document.user_id) {
+                profile.compliance_documents.push(document_id);
+                profiles.insert(document.user_id, profile);
+            }
+        });
+
+        if let Some(mut user) = USERS.with(|u| u.borrow().get(&document.user_id)) {
+            refresh_verification(&mut user, caller);
+        }
+    }
+
+    Ok(document)
+}
+
+#[query]
+fn get_onboarding_status() -> Result<OnboardingStatus, String> {
+    let caller = msg_caller();
+    let user = USERS.with(|u| u.borrow().get(&caller).ok_or("User not found".to_string()))?;
+
+    Ok(OnboardingStatus {
+        user_id: caller,
+        role: user.role.clone(),
+        is_verified: user.is_verified,
+        required_documents: required_documents(&user.role),
+        missing_documents: missing_documents(&user),
+        documents: documents_for(caller),
+    })
+}
+
+#[query]
+fn get_certification_alerts() -> Vec<CertificationAlert> {
+    let caller = msg_caller();
+    let prefix = format!("{}|", caller.to_text());
+
+    CERTIFICATION_ALERTS.with(|a| {
+        a.borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, alert)| alert)
+            .collect()
+    })
+}
+
+#[query]
+fn get_pending_documents() -> Result<Vec<OnboardingDocument>, String> {
+    require_admin(&msg_caller())?;
+
+    Ok(DOCUMENTS.with(|d| {
+        d.borrow()
+            .iter()
+            .filter(|(_, document)| document.status == DocumentStatus::Pending)
+            .map(|(_, document)| document)
+            .collect()
+    }))
+}
+
+fn membership_key(organization_id: &str, member: &Principal) -> String {
+    format!("{}|{}", organization_id, member.to_text())
+}
+
+fn members_of(organization_id: &str) -> Vec<Membership> {
+    let prefix = format!("{}|", organization_id);
+
+    MEMBERSHIPS.with(|m| {
+        m.borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, membership)| membership)
+            .collect()
+    })
+}
+
+fn require_org_owner(organization_id: &str, principal: &Principal) -> Result<(), String> {
+    let membership =
+        MEMBERSHIPS.with(|m| m.borrow().get(&membership_key(organization_id, principal)));
+
+    match membership {
+        Some(membership) if membership.role == OrgRole::Owner => Ok(()),
+        _ => Err("Only organization owners can perform this action".to_string()),
+    }
+}
+
+// Organizations must always keep at least one owner.
+fn is_last_owner(organization_id: &str, member: &Principal) -> bool {
+    let owners: Vec<Membership> = members_of(organization_id)
+        .into_iter()
+        .filter(|membership| membership.role == OrgRole::Owner)
+        .collect();
+
+    owners.len() == 1 && owners[0].member == *member
+}
+
+#[update]
+fn create_organization(name: String) -> Result<Organization, String> {
+    let caller = msg_caller();
+    let current_time = time();
+
+    require_active(&caller)?;
+
+    if name.trim().is_empty() {
+        return Err("Organization name is required".to_string());
+    }
+    if name.chars().count() > MAX_ORGANIZATION_NAME_LENGTH {
+        return Err(format!(
+            "Organization name must be at most {} characters",
+            MAX_ORGANIZATION_NAME_LENGTH
+        ));
+    }
+
+    // Organizations are never removed, so the count is a sequence number that keeps ids
+    // unique when one user creates several in a round.
+    let sequence = ORGANIZATIONS.with(|o| o.borrow().len());
+    let organization = Organization {
+        id: format!("{}-{:010}", current_time, sequence),
+        name,
+        created_by: caller,
+        created_at: current_time,
+        updated_at: current_time,
+    };
+
+    ORGANIZATIONS.with(|o| {
+        o.borrow_mut()
+            .insert(organization.id.clone(), organization.clone())
+    });
+    MEMBERSHIPS.with(|m| {
+        m.borrow_mut().insert(
+            membership_key(&organization.id, &caller),
+            Membership {
+                organization_id: organization.id.clone(),
+                member: caller,
+                role: OrgRole::Owner,
+                joined_at: current_time,
+            },
+        )
+    });
+
+    Ok(organization)
+}
+
+#[update]
+fn invite_member(
+    organization_id: String,
+    invitee: Principal,
+    role: OrgRole,
+) -> Result<Invitation, String> {
+    let caller = msg_caller();
+    require_org_owner(&organization_id, &caller)?;
+
+    if !USERS.with(|u| u.borrow().contains_key(&invitee)) {
+        return Err("User not found".to_string());
+    }
+
+    if MEMBERSHIPS.with(|m| {
+        m.borrow()
+            .contains_key(&membership_key(&organization_id, &invitee))
+    }) {
+        return Err("User is already a member of this organization".to_string());
+    }
+
+    // Invitations are never removed, so the count is a sequence number that keeps ids unique
+    // when the same user is invited twice in one round.
+    let sequence = INVITATIONS.with(|i| i.borrow().len());
+    let invitation = Invitation {
+        id: format!("{}-{:010}", time(), sequence),
+        organization_id,
+        invitee,
+        role,
+        invited_by: caller,
+        status: InvitationStatus::Pending,
+        created_at: time(),
+        responded_at: None,
+    };
+
+    INVITATIONS.with(|i| {
+        i.borrow_mut()
+            .insert(invitation.id.clone(), invitation.clone())
+    });
+
+    Ok(invitation)
+}
+
+fn get_pending_invitation(invitation_id: &str) -> Result<Invitation, String> {
+    let invitation = INVITATIONS.with(|i| {
+        i.borrow()
+            .get(&invitation_id.to_string())
+            .ok_or("Invitation not found".to_string())
+    })?;
+
+    if invitation.status != InvitationStatus::Pending {
+        return Err("Invitation is no longer pending".to_string());
+    }
+
+    Ok(invitation)
+}
+
+#[update]
+fn respond_to_invitation(invitation_id: String, accept: bool) -> Result<Invitation, String> {
+    let caller = msg_caller();
+    let current_time = time();
+
+    require_active(&caller)?;
+    let mut invitation = get_pending_invitation(&invitation_id)?;
+
+    if invitation.invitee != caller {
+        return Err("Not authorized to respond to this invitation".to_string());
     }
-    if let Some(address) = address {
-        user.address = address;
+
+    // Role changes for existing members go through set_member_role, which protects the last
+    // owner.
+    let key = membership_key(&invitation.organization_id, &caller);
+    if accept && MEMBERSHIPS.with(|m| m.borrow().contains_key(&key)) {
+        return Err("Already a member of this organization".to_string());
     }
-    if let Some(phone) = phone {
-        user.phone = phone;
+
+    invitation.status = if accept {
+        InvitationStatus::Accepted
+    } else {
+        InvitationStatus::Declined
+    };
+    invitation.responded_at = Some(current_time);
+
+    if accept {
+        MEMBERSHIPS.with(|m| {
+            m.borrow_mut().insert(
+                key,
+                Membership {
+                    organization_id: invitation.organization_id.clone(),
+                    member: caller,
+                    role: invitation.role.clone(),
+                    joined_at: current_time,
+                },
+            )
+        });
     }
 
-    user.updated_at = current_time;
+    INVITATIONS.with(|i| i.borrow_mut().insert(invitation_id, invitation.clone()));
 
-    USERS.with(|u| u.borrow_mut().insert(caller, user.clone()));
+    Ok(invitation)
+}
 
-    // Update profile
-    let mut profile = USER_PROFILES.with(|p| {
-        p.borrow()
-            .get(&caller)
-            .ok_or("Profile not found".to_string())
+#[update]
+fn revoke_invitation(invitation_id: String) -> Result<Invitation, String> {
+    let mut invitation = get_pending_invitation(&invitation_id)?;
+    require_org_owner(&invitation.organization_id, &msg_caller())?;
+
+    invitation.status = InvitationStatus::Revoked;
+    invitation.responded_at = Some(time());
+
+    INVITATIONS.with(|i| i.borrow_mut().insert(invitation_id, invitation.clone()));
+
+    Ok(invitation)
+}
+
+#[update]
+fn set_member_role(
+    organization_id: String,
+    member: Principal,
+    role: OrgRole,
+) -> Result<Membership, String> {
+    require_org_owner(&organization_id, &msg_caller())?;
+
+    let key = membership_key(&organization_id, &member);
+    let mut membership = MEMBERSHIPS.with(|m| {
+        m.borrow()
+            .get(&key)
+            .ok_or("Membership not found".to_string())
     })?;
 
-    profile.user = user.clone();
-    USER_PROFILES.with(|p| p.borrow_mut().insert(caller, profile));
+    if role != OrgRole::Owner && is_last_owner(&organization_id, &member) {
+        return Err("Cannot demote the last owner of an organization".to_string());
+    }
 
-    Ok(user)
+    membership.role = role;
+    MEMBERSHIPS.with(|m| m.borrow_mut().insert(key, membership.clone()));
+
+    Ok(membership)
 }
 
+// Owners can remove any member; other members can only remove themselves.
 #[update]
-fn verify_user(user_id: Principal) -> Result<User, String> {
-    let mut user = USERS.with(|u| u.borrow().get(&user_id).ok_or("User not found".to_string()))?;
+fn remove_member(organization_id: String, member: Principal) -> Result<(), String> {
+    let caller = msg_caller();
+    if caller != member {
+        require_org_owner(&organization_id, &caller)?;
+    }
 
-    user.is_verified = true;
-    user.updated_at = time();
+    let key = membership_key(&organization_id, &member);
+    if !MEMBERSHIPS.with(|m| m.borrow().contains_key(&key)) {
+        return Err("Membership not found".to_string());
+    }
 
-    USERS.with(|u| u.borrow_mut().insert(user_id, user.clone()));
+    if is_last_owner(&organization_id, &member) {
+        return Err("Cannot remove the last owner of an organization".to_string());
+    }
 
-    Ok(user)
+    MEMBERSHIPS.with(|m| m.borrow_mut().remove(&key));
+
+    Ok(())
+}
+
+#[query]
+fn get_organization(organization_id: String) -> Result<Organization, String> {
+    ORGANIZATIONS.with(|o| {
+        o.borrow()
+            .get(&organization_id)
+            .ok_or("Organization not found".to_string())
+    })
+}
+
+#[query]
+fn get_organization_members(organization_id: String) -> Result<Vec<Membership>, String> {
+    let caller = msg_caller();
+    if !is_admin(&caller)
+        && !MEMBERSHIPS.with(|m| {
+            m.borrow()
+                .contains_key(&membership_key(&organization_id, &caller))
+        })
+    {
+        return Err("Only members can view an organization's members".to_string());
+    }
+
+    Ok(members_of(&organization_id))
+}
+
+// Used by other canisters to check what a principal may do on an organization's behalf.
+#[query]
+fn get_membership(organization_id: String, member: Principal) -> Option<Membership> {
+    MEMBERSHIPS.with(|m| m.borrow().get(&membership_key(&organization_id, &member)))
+}
+
+#[query]
+fn get_my_organizations() -> Vec<Organization> {
+    let caller = msg_caller();
+
+    MEMBERSHIPS.with(|m| {
+        m.borrow()
+            .iter()
+            .filter(|(_, membership)| membership.member == caller)
+            .filter_map(|(_, membership)| {
+                ORGANIZATIONS.with(|o| o.borrow().get(&membership.organization_id))
+            })
+            .collect()
+    })
+}
+
+#[query]
+fn get_my_invitations() -> Vec<Invitation> {
+    let caller = msg_caller();
+
+    INVITATIONS.with(|i| {
+        i.borrow()
+            .iter()
+            .filter(|(_, invitation)| {
+                invitation.invitee == caller && invitation.status == InvitationStatus::Pending
+            })
+            .map(|(_, invitation)| invitation)
+            .collect()
+    })
+}
+
+// Users can delegate for themselves; only organization owners can delegate for an
+// organization.
+fn require_delegation_grantor(
+    subject: &DelegationSubject,
+    principal: &Principal,
+) -> Result<(), String> {
+    match subject {
+        DelegationSubject::User(user) if user == principal => Ok(()),
+        DelegationSubject::User(_) => {
+            Err("Users can only delegate on their own behalf".to_string())
+        }
+        DelegationSubject::Organization(organization_id) => {
+            require_org_owner(organization_id, principal)
+        }
+    }
 }
 
 #[update]
-fn add_certification(certification: String) -> Result<UserProfile, String> {
-    let caller = ic_cdk::caller();
+fn grant_delegation(
+    delegate: Principal,
+    subject: DelegationSubject,
+    scopes: Vec<DelegationScope>,
+    expires_at: u64,
+) -> Result<Delegation, String> {
+    let caller = msg_caller();
+    let current_time = time();
 
-    let mut profile = USER_PROFILES.with(|p| {
-        p.borrow()
-            .get(&caller)
-            .ok_or("Profile not found".to_string())
-    })?;
+    require_active(&caller)?;
+    require_delegation_grantor(&subject, &caller)?;
 
-    profile.certifications.push(certification);
+    if delegate == caller {
+        return Err("Cannot delegate to yourself".to_string());
+    }
 
-    USER_PROFILES.with(|p| p.borrow_mut().insert(caller, profile.clone()));
+    if scopes.is_empty() {
+        return Err("At least one scope is required".to_string());
+    }
 
-    Ok(profile)
+    if expires_at <= current_time {
+        return Err("Delegation expiry must be in the future".to_string());
+    }
+
+    // Revoked delegations are kept, so the count is a sequence number that keeps ids unique
+    // when the same delegate is granted twice in one round.
+    let sequence = DELEGATIONS.with(|d| d.borrow().len());
+    let delegation = Delegation {
+        id: format!("{}-{:010}", current_time, sequence),
+        delegate,
+        subject,
+        scopes,
+        expires_at,
+        granted_by: caller,
+        created_at: current_time,
+        revoked_at: None,
+    };
+
+    DELEGATIONS.with(|d| {
+        d.borrow_mut()
+            .insert(delegation.id.clone(), delegation.clone())
+    });
+
+    Ok(delegation)
 }
 
 #[update]
-fn add_compliance_document(document: String) -> Result<UserProfile, String> {
-    let caller = ic_cdk::caller();
+fn revoke_delegation(delegation_id: String) -> Result<Delegation, String> {
+    let caller = msg_caller();
 
-    let mut profile = USER_PROFILES.with(|p| {
-        p.borrow()
-            .get(&caller)
-            .ok_or("Profile not found".to_string())
+    let mut delegation = DELEGATIONS.with(|d| {
+        d.borrow()
+            .get(&delegation_id)
+            .ok_or("Delegation not found".to_string())
     })?;
 
-    profile.compliance_documents.push(document);
+    if delegation.granted_by != caller {
+        require_delegation_grantor(&delegation.subject, &caller)?;
+    }
 
-    USER_PROFILES.with(|p| p.borrow_mut().insert(caller, profile.clone()));
+    if delegation.revoked_at.is_some() {
+        return Err("Delegation has already been revoked".to_string());
+    }
 
-    Ok(profile)
+    delegation.revoked_at = Some(time());
+    DELEGATIONS.with(|d| d.borrow_mut().insert(delegation_id, delegation.clone()));
+
+    Ok(delegation)
+}
+
+// Used by other canisters to validate calls made under a delegation.
+#[query]
+fn get_delegation(delegation_id: String) -> Option<Delegation> {
+    DELEGATIONS.with(|d| d.borrow().get(&delegation_id))
+}
+
+// Delegations the caller has granted or been granted.
+#[query]
+fn get_my_delegations() -> Vec<Delegation> {
+    let caller = msg_caller();
+
+    DELEGATIONS.with(|d| {
+        d.borrow()
+            .iter()
+            .filter(|(_, delegation)| {
+                delegation.granted_by == caller || delegation.delegate == caller
+            })
+            .map(|(_, delegation)| delegation)
+            .collect()
+    })
+}
+
+#[update]
+fn set_canister_config(config: CanisterConfig) -> Result<CanisterConfig, String> {
+    if !is_controller(&msg_caller()) {
+        return Err("Only controllers can change the canister config".to_string());
+    }
+
+    CANISTER_CONFIG
+        .with(|c| c.borrow_mut().set(config.clone()))
+        .map_err(|e| format!("{:?}", e))?;
+
+    Ok(config)
+}
+
+#[query]
+fn get_canister_config() -> CanisterConfig {
+    CANISTER_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+// Guard for the bulk listing endpoints.
+fn reject_anonymous() -> Result<(), String> {
+    if msg_caller() == Principal::anonymous() {
+        return Err("Anonymous callers cannot list users".to_string());
+    }
+
+    Ok(())
+}
+
+// Contact details and other private fields are only shown to the user themselves and admins,
+// plus counterparties with an active transfer through get_private_profile.
+fn can_view_private(caller: &Principal, user_id: &Principal) -> bool {
+    caller == user_id || is_admin(caller)
+}
+
+// The public view of a user: name, company, role and verification state.
+fn public_user(mut user: User) -> User {
+    user.email = String::new();
+    user.phone = String::new();
+    user.address = String::new();
+    user.metadata = vec![];
+    user
+}
+
+fn public_profile(mut profile: UserProfile) -> UserProfile {
+    profile.user = public_user(profile.user);
+    profile.compliance_documents = vec![];
+    profile.business_license = None;
+    profile.tax_id = None;
+    profile
+}
+
+fn visible_user(caller: &Principal, user: User) -> User {
+    if can_view_private(caller, &user.id) {
+        user
+    } else {
+        public_user(user)
+    }
+}
+
+async fn has_active_transfer(a: Principal, b: Principal) -> Result<bool, String> {
+    let canister = CANISTER_CONFIG
+        .with(|c| c.borrow().get().supply_chain_canister)
+        .ok_or("Supply chain canister is not configured".to_string())?;
+
+    Call::bounded_wait(canister, "has_active_transfer")
+        .with_args(&(a, b))
+        .await
+        .map_err(|e| format!("Failed to look up transfers: {}", e))?
+        .candid::<Result<bool, String>>()
+        .map_err(|e| format!("Failed to decode transfers: {}", e))?
 }
 
 #[query]
 fn get_user(user_id: Principal) -> Result<User, String> {
-    USERS.with(|u| u.borrow().get(&user_id).ok_or("User not found".to_string()))
+    let user = USERS.with(|u| u.borrow().get(&user_id).ok_or("User not found".to_string()))?;
+    Ok(visible_user(&msg_caller(), user))
 }
 
 #[query]
 fn get_user_profile(user_id: Principal) -> Result<UserProfile, String> {
-    USER_PROFILES.with(|p| {
+    let profile = USER_PROFILES.with(|p| {
+        p.borrow()
+            .get(&user_id)
+            .ok_or("Profile not found".to_string())
+    })?;
+
+    if can_view_private(&msg_caller(), &user_id) {
+        Ok(profile)
+    } else {
+        Ok(public_profile(profile))
+    }
+}
+
+// The full profile, for callers that also qualify as a counterparty of the user.
+#[query(composite = true)]
+async fn get_private_profile(user_id: Principal) -> Result<UserProfile, String> {
+    let caller = msg_caller();
+
+    let profile = USER_PROFILES.with(|p| {
         p.borrow()
             .get(&user_id)
             .ok_or("Profile not found".to_string())
+    })?;
+
+    if !can_view_private(&caller, &user_id) && !has_active_transfer(caller, user_id).await? {
+        return Err("Not authorized to view this user's private details".to_string());
+    }
+
+    Ok(profile)
+}
+
+async fn fetch_reputation_scores(users: Vec<Principal>) -> Result<Vec<(Principal, f64)>, String> {
+    let canister = CANISTER_CONFIG
+        .with(|c| c.borrow().get().rating_canister)
+        .ok_or("Rating canister is not configured".to_string())?;
+
+    Call::bounded_wait(canister, "get_reputation_scores")
+        .with_arg(users)
+        .await
+        .map_err(|e| format!("Failed to look up reputation: {}", e))?
+        .candid::<Vec<(Principal, f64)>>()
+        .map_err(|e| format!("Failed to decode reputation: {}", e))
+}
+
+// The index prefix to scan for a query: the longest text token if there is one, otherwise
+// the region, otherwise the role. With no criteria every user is listed via the role keys.
+fn search_prefix(query: &UserSearchQuery, tokens: &[String]) -> String {
+    if let Some(token) = tokens.iter().max_by_key(|token| token.len()) {
+        format!("name:{}", token)
+    } else if let Some(region) = query.region.as_deref().and_then(region_of) {
+        format!("region:{}|", region)
+    } else if let Some(role) = &query.role {
+        format!("role:{:?}|", role)
+    } else {
+        "role:".to_string()
+    }
+}
+
+fn matches_search(user: &User, query: &UserSearchQuery, tokens: &[String]) -> bool {
+    let words = search_words(user);
+
+    user.status == AccountStatus::Active
+        && tokens
+            .iter()
+            .all(|token| words.iter().any(|word| word.starts_with(token.as_str())))
+        && query.role.as_ref().is_none_or(|role| user.role == *role)
+        && query
+            .is_verified
+            .is_none_or(|is_verified| user.is_verified == is_verified)
+        && query
+            .region
+            .as_deref()
+            .is_none_or(|region| region_of(region).is_some_and(|r| shared_region(user) == Some(r)))
+}
+
+// Reads index entries after `start` until `wanted` users match the query's local filters.
+// Returns the matches, the last key read, and whether the prefix was exhausted.
+fn next_search_batch(
+    prefix: &str,
+    start: Bound<String>,
+    query: &UserSearchQuery,
+    tokens: &[String],
+    wanted: usize,
+    budget: &mut usize,
+) -> (Vec<User>, Option<String>, bool) {
+    let mut users = vec![];
+    let mut last_key = None;
+
+    USER_SEARCH_INDEX.with(|i| {
+        let index = i.borrow();
+        let mut entries = index
+            .range((start, Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .peekable();
+
+        while users.len() < wanted && *budget > 0 {
+            let (key, user_id) = match entries.next() {
+                Some(entry) => entry,
+                None => break,
+            };
+            *budget -= 1;
+            last_key = Some(key.clone());
+
+            let user = match USERS.with(|u| u.borrow().get(&user_id)) {
+                Some(user) => user,
+                None => continue,
+            };
+
+            // A user with several words matching the scanned prefix is only taken once, at
+            // the first of them.
+            if let Some(token) = prefix.strip_prefix("name:") {
+                let word = key["name:".len()..].split('|').next();
+                let first_match = search_words(&user)
+                    .into_iter()
+                    .find(|w| w.starts_with(token));
+                if first_match.as_deref() != word {
+                    continue;
+                }
+            }
+
+            if matches_search(&user, query, tokens) {
+                users.push(user);
+            }
+        }
+
+        let exhausted = entries.peek().is_none();
+        (users, last_key, exhausted)
+    })
+}
+
+// Searches the user directory. Results come back in index order, `limit` at a time; pass
+// `next_cursor` back as `cursor` for the next page.
+#[query(composite = true, guard = "reject_anonymous")]
+async fn search_users(query: UserSearchQuery) -> Result<UserSearchPage, String> {
+    let caller = msg_caller();
+
+    let limit = query.limit.clamp(1, MAX_SEARCH_PAGE_SIZE) as usize;
+    let tokens = search_tokens(query.text.as_deref().unwrap_or(""));
+    let prefix = search_prefix(&query, &tokens);
+
+    let mut start = match &query.cursor {
+        Some(cursor) => Bound::Excluded(cursor.clone()),
+        None => Bound::Included(prefix.clone()),
+    };
+    let mut budget = MAX_SEARCH_SCAN;
+    let mut users = vec![];
+
+    let next_cursor = loop {
+        let (batch, last_key, exhausted) = next_search_batch(
+            &prefix,
+            start,
+            &query,
+            &tokens,
+            limit - users.len(),
+            &mut budget,
+        );
+
+        match query.min_reputation {
+            Some(min_reputation) if !batch.is_empty() => {
+                let scores =
+                    fetch_reputation_scores(batch.iter().map(|user| user.id).collect()).await?;
+                users.extend(
+                    batch
+                        .into_iter()
+                        .zip(scores)
+                        .filter(|(_, (_, score))| *score >= min_reputation)
+                        .map(|(user, _)| user),
+                );
+            }
+            _ => users.extend(batch),
+        }
+
+        if exhausted {
+            break None;
+        }
+        if users.len() >= limit || budget == 0 {
+            break last_key;
+        }
+        start = match last_key {
+            Some(key) => Bound::Excluded(key),
+            None => break None,
+        };
+    };
+
+    Ok(UserSearchPage {
+        users: users
+            .into_iter()
+            .map(|user| visible_user(&caller, user))
+            .collect(),
+        next_cursor,
     })
 }
 
 #[query]
 fn get_current_user() -> Result<User, String> {
-    let caller = ic_cdk::caller();
+    let caller = msg_caller();
     USERS.with(|u| u.borrow().get(&caller).ok_or("User not found".to_string()))
 }
 
-#[query]
+#[query(guard = "reject_anonymous")]
 fn get_users_by_role(role: UserRole) -> Vec<User> {
+    let caller = msg_caller();
     USERS.with(|u| {
         u.borrow()
             .iter()
             .filter(|(_, user)| std::mem::discriminant(&user.role) == std::mem::discriminant(&role))
-            .map(|(_, user)| user)
+            .map(|(_, user)| visible_user(&caller, user))
             .collect()
     })
 }
 
-#[query]
+#[query(guard = "reject_anonymous")]
 fn get_all_users() -> Vec<User> {
-    USERS.with(|u| u.borrow().iter().map(|(_, user)| user).collect())
+    let caller = msg_caller();
+    USERS.with(|u| {
+        u.borrow()
+            .iter()
+            .map(|(_, user)| visible_user(&caller, user))
+            .collect()
+    })
 }
 
-#[query]
+#[query(guard = "reject_anonymous")]
 fn get_verified_users() -> Vec<User> {
+    let caller = msg_caller();
     USERS.with(|u| {
         u.borrow()
             .iter()
             .filter(|(_, user)| user.is_verified)
-            .map(|(_, user)| user)
+            .map(|(_, user)| visible_user(&caller, user))
             .collect()
     })
 }
 
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending_document() -> OnboardingDocument {
+        OnboardingDocument {
+            id: "1-0000000000".to_string(),
+            user_id: Principal::from_slice(&[1]),
+            document_type: DocumentType::BusinessLicense,
+            document_hash: "a".repeat(64),
+            metadata: vec![("issuer".to_string(), "Registry".to_string())],
+            submitted_at: 1,
+            status: DocumentStatus::Pending,
+            reviewed_by: None,
+            reviewed_at: None,
+            rejection_reason: None,
+        }
+    }
+
+    #[test]
+    fn document_requires_a_bounded_hash() {
+        assert!(validate_document("", &[]).is_err());
+        assert!(validate_document(&"a".repeat(MAX_DOCUMENT_HASH_LENGTH), &[]).is_ok());
+        assert!(validate_document(&"a".repeat(MAX_DOCUMENT_HASH_LENGTH + 1), &[]).is_err());
+    }
+
+    #[test]
+    fn document_metadata_is_bounded() {
+        let field = "k".repeat(MAX_DOCUMENT_METADATA_FIELD_LENGTH);
+        let full = vec![(field.clone(), field.clone()); MAX_DOCUMENT_METADATA_ENTRIES];
+        assert!(validate_document("hash", &full).is_ok());
+
+        let too_many = vec![(field.clone(), field.clone()); MAX_DOCUMENT_METADATA_ENTRIES + 1];
+        assert!(validate_document("hash", &too_many).is_err());
+
+        let long_value = vec![("key".to_string(), format!("{}v", field))];
+        assert!(validate_document("hash", &long_value).is_err());
+    }
+
+    #[test]
+    fn largest_valid_document_fits_its_storage_bound() {
+        let field = "k".repeat(MAX_DOCUMENT_METADATA_FIELD_LENGTH);
+        let mut document = pending_document();
+        document.document_hash = "a".repeat(MAX_DOCUMENT_HASH_LENGTH);
+        document.metadata = vec![(field.clone(), field); MAX_DOCUMENT_METADATA_ENTRIES];
+
+        let rejected = apply_review(
+            document,
+            false,
+            Some("r".repeat(MAX_REJECTION_REASON_LENGTH)),
+            Principal::from_slice(&[2]),
+            2,
+        );
+
+        assert!(rejected.is_ok());
+    }
+
+    #[test]
+    fn approving_a_pending_document_records_the_reviewer() {
+        let reviewer = Principal::from_slice(&[2]);
+
+        let approved = apply_review(
+            pending_document(),
+            true,
+            Some("ignored".to_string()),
+            reviewer,
+            5,
+        )
+        .unwrap();
+
+        assert_eq!(approved.status, DocumentStatus::Approved);
+        assert_eq!(approved.reviewed_by, Some(reviewer));
+        assert_eq!(approved.reviewed_at, Some(5));
+        assert_eq!(approved.rejection_reason, None);
+    }
+
+    #[test]
+    fn rejecting_requires_a_bounded_reason() {
+        let reviewer = Principal::from_slice(&[2]);
+
+        assert!(apply_review(pending_document(), false, None, reviewer, 5).is_err());
+        let too_long = Some("r".repeat(MAX_REJECTION_REASON_LENGTH + 1));
+        assert!(apply_review(pending_document(), false, too_long, reviewer, 5).is_err());
+
+        let rejected = apply_review(
+            pending_document(),
+            false,
+            Some("Expired licence".to_string()),
+            reviewer,
+            5,
+        )
+        .unwrap();
+        assert_eq!(rejected.status, DocumentStatus::Rejected);
+        assert_eq!(
+            rejected.rejection_reason,
+            Some("Expired licence".to_string())
+        );
+    }
+
+    fn user(n: u8, name: &str, company_name: &str) -> User {
+        User {
+            id: Principal::from_slice(&[n]),
+            name: name.to_string(),
+            email: String::new(),
+            role: UserRole::Supplier,
+            company_name: company_name.to_string(),
+            address: "12 Dock Road, Mumbai, Maharashtra".to_string(),
+            phone: String::new(),
+            share_region: false,
+            is_verified: true,
+            status: AccountStatus::Active,
+            created_at: 0,
+            updated_at: 0,
+            metadata: vec![],
+        }
+    }
+
+    fn query(text: Option<&str>) -> UserSearchQuery {
+        UserSearchQuery {
+            text: text.map(str::to_string),
+            role: None,
+            is_verified: None,
+            region: None,
+            min_reputation: None,
+            cursor: None,
+            limit: 10,
+        }
+    }
+
+    fn names(users: &[User]) -> Vec<String> {
+        users.iter().map(|user| user.name.clone()).collect()
+    }
+
+    // Runs one search page over the index, as search_users does without reputation filtering.
+    fn search_page(
+        query: &UserSearchQuery,
+        start: Bound<String>,
+        wanted: usize,
+        mut budget: usize,
+    ) -> (Vec<User>, Option<String>, bool) {
+        let tokens = search_tokens(query.text.as_deref().unwrap_or(""));
+        let prefix = search_prefix(query, &tokens);
+        let start = match start {
+            Bound::Unbounded => Bound::Included(prefix.clone()),
+            start => start,
+        };
+        next_search_batch(&prefix, start, query, &tokens, wanted, &mut budget)
+    }
+
+    #[test]
+    fn search_pages_continue_after_the_cursor() {
+        for n in 1..=5 {
+            store_user(&user(n, &format!("Trader {}", n), "Acme"));
+        }
+        let acme = query(Some("acme"));
+
+        let (first, cursor, exhausted) = search_page(&acme, Bound::Unbounded, 2, MAX_SEARCH_SCAN);
+        assert_eq!(first.len(), 2);
+        assert!(!exhausted);
+
+        let (second, cursor, _) =
+            search_page(&acme, Bound::Excluded(cursor.unwrap()), 2, MAX_SEARCH_SCAN);
+        let (third, _, exhausted) =
+            search_page(&acme, Bound::Excluded(cursor.unwrap()), 2, MAX_SEARCH_SCAN);
+        assert_eq!(third.len(), 1);
+        assert!(exhausted);
+
+        let mut seen: Vec<String> = [first, second, third]
+            .concat()
+            .iter()
+            .map(|u| u.name.clone())
+            .collect();
+        seen.sort();
+        assert_eq!(
+            seen,
+            (1..=5).map(|n| format!("Trader {}", n)).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn user_with_several_matching_words_is_returned_once() {
+        store_user(&user(1, "Acme Acmeco", "Acme Holdings"));
+
+        let (users, _, exhausted) =
+            search_page(&query(Some("acm")), Bound::Unbounded, 10, MAX_SEARCH_SCAN);
+
+        assert_eq!(names(&users), vec!["Acme Acmeco"]);
+        assert!(exhausted);
+    }
+
+    #[test]
+    fn search_stops_when_the_scan_budget_runs_out() {
+        store_user(&user(1, "Acme North", ""));
+        store_user(&user(2, "Acme South", ""));
+        let acme = query(Some("acme"));
+
+        let (first, cursor, exhausted) = search_page(&acme, Bound::Unbounded, 10, 1);
+        assert_eq!(first.len(), 1);
+        assert!(!exhausted);
+
+        let (rest, _, exhausted) =
+            search_page(&acme, Bound::Excluded(cursor.unwrap()), 10, MAX_SEARCH_SCAN);
+        assert_eq!(rest.len(), 1);
+        assert_ne!(rest[0].id, first[0].id);
+        assert!(exhausted);
+    }
+
+    #[test]
+    fn region_is_only_searchable_once_shared() {
+        let mut sharing = user(1, "Shared", "");
+        sharing.share_region = true;
+        store_user(&sharing);
+        store_user(&user(2, "Private", ""));
+        let mut by_region = query(None);
+        by_region.region = Some("Maharashtra".to_string());
+
+        let (users, _, _) = search_page(&by_region, Bound::Unbounded, 10, MAX_SEARCH_SCAN);
+
+        assert_eq!(names(&users), vec!["Shared"]);
+    }
+
+    #[test]
+    fn inactive_users_are_not_listed() {
+        let mut suspended = user(1, "Acme", "");
+        suspended.status = AccountStatus::Suspended;
+        store_user(&suspended);
+        store_user(&user(2, "Acme", ""));
+
+        let (users, _, _) = search_page(&query(None), Bound::Unbounded, 10, MAX_SEARCH_SCAN);
+
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].id, Principal::from_slice(&[2]));
+    }
+
+    #[test]
+    fn renewed_certification_gets_a_new_alert_id() {
+        let user = Principal::from_slice(&[1]);
+
+        assert_ne!(
+            certification_alert_id(user, "C-1", 100),
+            certification_alert_id(user, "C-1", 200)
+        );
+    }
+
+    #[test]
+    fn legacy_alerts_are_moved_to_their_current_key() {
+        let user = Principal::from_slice(&[1]);
+        let legacy_id = format!("{}|C-1", user.to_text());
+        let alert = CertificationAlert {
+            id: legacy_id.clone(),
+            user_id: user,
+            scheme: "ISO9001".to_string(),
+            certificate_number: "C-1".to_string(),
+            valid_until: 100,
+            created_at: 1,
+        };
+        CERTIFICATION_ALERTS.with(|a| a.borrow_mut().insert(legacy_id.clone(), alert));
+
+        rekey_certification_alerts();
+
+        let current_id = certification_alert_id(user, "C-1", 100);
+        CERTIFICATION_ALERTS.with(|a| {
+            let alerts = a.borrow();
+            assert!(!alerts.contains_key(&legacy_id));
+            assert_eq!(alerts.get(&current_id).unwrap().id, current_id);
+        });
+    }
+
+    #[test]
+    fn reviewed_documents_cannot_be_reviewed_again() {
+        let reviewer = Principal::from_slice(&[2]);
+        let approved = apply_review(pending_document(), true, None, reviewer, 5).unwrap();
+
+        assert!(apply_review(approved, false, Some("Late".to_string()), reviewer, 6).is_err());
+    }
+}